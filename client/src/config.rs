@@ -1,9 +1,10 @@
 use atomicwrites::{AllowOverwrite, AtomicFile};
 use kludgine::prelude::*;
 use std::{fs, io::Write, path::PathBuf};
+use tracing::error;
 
 lazy_static! {
-    static ref CONFIG: KludgineHandle<UserConfig> = { KludgineHandle::new(UserConfig::load()) };
+    static ref CONFIG: KludgineHandle<UserConfig> = KludgineHandle::new(UserConfig::load());
 }
 
 use serde_derive::{Deserialize, Serialize};
@@ -42,10 +43,10 @@ impl UserConfig {
         if let Ok(data) = toml::to_string(self) {
             let af = AtomicFile::new(config_path, AllowOverwrite);
             if let Err(err) = af.write(|f| f.write_all(data.as_bytes())) {
-                println!("Error saving config {}", err);
+                error!(error = %err, "Error saving config");
             }
         } else {
-            println!("Error serializing config");
+            error!("Error serializing config");
         }
     }
 
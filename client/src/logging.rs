@@ -0,0 +1,17 @@
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_LEVEL: &str = "info";
+
+/// Installs the global subscriber, filtered by `LOG_LEVEL` if it's set.
+pub fn init() {
+    let level = std::env::var("LOG_LEVEL").unwrap_or_else(|_| DEFAULT_LEVEL.to_owned());
+    let (filter, invalid) = match EnvFilter::try_new(&level) {
+        Ok(filter) => (filter, None),
+        Err(err) => (EnvFilter::new(DEFAULT_LEVEL), Some(err)),
+    };
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+    if let Some(err) = invalid {
+        warn!(%level, error = %err, "Invalid LOG_LEVEL, using {}", DEFAULT_LEVEL);
+    }
+}
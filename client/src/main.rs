@@ -1,10 +1,12 @@
+mod logging;
 mod network;
 use network::{LoginState, Network};
-use shared::ServerRequest;
+use std::time::Instant;
 
 fn main() {
     dotenv::dotenv().unwrap_or_default();
-    SingleWindowApplication::run_with(|| CosmicCantina::new());
+    logging::init();
+    SingleWindowApplication::run_with(CosmicCantina::new);
 }
 
 use kludgine::prelude::*;
@@ -84,8 +86,7 @@ impl Window for CosmicCantina {
                     let delta_to_center = (max_x_offset / 2.0 - main_menu.x_offset).abs();
                     let percent_from_center = delta_to_center / (max_x_offset / 2.0);
                     let speed = (0.2 + (1.0 - percent_from_center) * 0.8) * 16.0;
-                    main_menu.x_offset =
-                        main_menu.x_offset + speed * pan_direction * elapsed.as_secs_f32();
+                    main_menu.x_offset += speed * pan_direction * elapsed.as_secs_f32();
                     if main_menu.x_offset <= 0.0 {
                         main_menu.x_offset = 0.0;
                         main_menu.pan_left = false;
@@ -108,11 +109,15 @@ impl Window for CosmicCantina {
     }
 
     async fn process_input(&mut self, event: InputEvent) -> KludgineResult<()> {
-        match event.event {
-            Event::MouseButton { .. } => {
-                Network::request(ServerRequest::AuthenticationUrl).await;
+        if let Event::MouseButton { .. } = event.event {
+            match Network::login_state().await {
+                LoginState::UpdateRequired { download_url, .. } => {
+                    webbrowser::open(&download_url).expect("Error launching URL");
+                }
+                _ => {
+                    Runtime::spawn(Network::log_in());
+                }
             }
-            _ => {}
         }
         Ok(())
     }
@@ -136,10 +141,11 @@ impl CosmicCantina {
                 ),
             )
             .await;
-        self.render_network_status(scene).await
+        self.render_network_status(scene).await?;
+        self.render_latency(scene).await
     }
 
-    async fn render_outside<'a>(&self, scene: &mut SceneTarget<'a>) -> KludgineResult<()> {
+    async fn render_outside<'a>(&self, _scene: &mut SceneTarget<'a>) -> KludgineResult<()> {
         Ok(())
     }
 
@@ -156,7 +162,7 @@ impl CosmicCantina {
                 .effective_style(scene),
             ),
             LoginState::Connected { .. } => Text::span(
-                format!("Connected"),
+                "Connected",
                 &Style {
                     font_family: Some("Press Start 2P".to_owned()),
                     font_size: Some(8.0),
@@ -165,6 +171,26 @@ impl CosmicCantina {
                 }
                 .effective_style(scene),
             ),
+            LoginState::Reconnecting {
+                attempt,
+                next_retry_at,
+            } => Text::span(
+                format!(
+                    "Reconnecting in {}s (attempt {})",
+                    next_retry_at
+                        .saturating_duration_since(Instant::now())
+                        .as_secs()
+                        + 1,
+                    attempt
+                ),
+                &Style {
+                    font_family: Some("Press Start 2P".to_owned()),
+                    font_size: Some(8.0),
+                    color: Some(Color::new(1.0, 0.5, 0.5, 1.0)),
+                    ..Default::default()
+                }
+                .effective_style(scene),
+            ),
             LoginState::LoggedOut => Text::span(
                 "Connecting...",
                 &Style {
@@ -175,8 +201,28 @@ impl CosmicCantina {
                 }
                 .effective_style(scene),
             ),
-            LoginState::Error { message } => Text::span(
-                format!("Error connecting. {}", message.unwrap_or_default()),
+            LoginState::UpdateRequired { minimum, .. } => Text::span(
+                format!("Version {} or newer is required. Click to update.", minimum),
+                &Style {
+                    font_family: Some("Press Start 2P".to_owned()),
+                    font_size: Some(8.0),
+                    color: Some(Color::new(1.0, 1.0, 0.5, 1.0)),
+                    ..Default::default()
+                }
+                .effective_style(scene),
+            ),
+            LoginState::LoginFailed { reason } => Text::span(
+                format!("{} Click to log in again.", reason.message()),
+                &Style {
+                    font_family: Some("Press Start 2P".to_owned()),
+                    font_size: Some(8.0),
+                    color: Some(Color::new(1.0, 0.5, 0.5, 1.0)),
+                    ..Default::default()
+                }
+                .effective_style(scene),
+            ),
+            LoginState::Error { code } => Text::span(
+                format!("Error: {}", code.message()),
                 &Style {
                     font_family: Some("Press Start 2P".to_owned()),
                     font_size: Some(8.0),
@@ -190,6 +236,24 @@ impl CosmicCantina {
             .await
     }
 
+    async fn render_latency<'a>(&self, scene: &mut SceneTarget<'a>) -> KludgineResult<()> {
+        if let Some(latency) = Network::latency().await {
+            let text = Text::span(
+                format!("Ping: {}ms", latency.as_millis()),
+                &Style {
+                    font_family: Some("Press Start 2P".to_owned()),
+                    font_size: Some(8.0),
+                    color: Some(Color::new(0.7, 0.7, 0.7, 1.0)),
+                    ..Default::default()
+                }
+                .effective_style(scene),
+            );
+            text.render_at(scene, Point::new(5.0, 15.0), TextWrap::NoWrap)
+                .await?;
+        }
+        Ok(())
+    }
+
     async fn render_inside_scene<'a>(
         &self,
         x_offset: f32,
@@ -1,29 +1,68 @@
 use crate::config::UserConfig;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use kludgine::prelude::*;
-use shared::{ServerRequest, ServerResponse, UserProfile};
-use std::time::Duration;
-use tokio::sync::mpsc::{
-    error::TryRecvError as TokioTryRecvError, Receiver as TokioReceiver, Sender as TokioSender,
+use rand::Rng;
+use shared::{
+    Codec, ErrorCode, LoginFailure, RequestEnvelope, RequestId, ResponseEnvelope, ServerRequest,
+    ServerResponse, UserProfile, WireFormat,
 };
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::sync::{
+    mpsc::{
+        error::TryRecvError as TokioTryRecvError, Receiver as TokioReceiver, Sender as TokioSender,
+    },
+    oneshot,
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
 use yarws::{Client, Msg};
 
 lazy_static! {
-    static ref NETWORK: KludgineHandle<Network> = { KludgineHandle::new(Network::new()) };
+    static ref NETWORK: KludgineHandle<Network> = KludgineHandle::new(Network::new());
 }
 
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(250);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+// How long a connection has to last before reconnecting starts over from the
+// initial delay. A server that accepts connections and drops them right away
+// is backed off from like one that refuses them.
+const STABLE_CONNECTION_TIME: Duration = Duration::from_secs(30);
+
 #[derive(Clone, Debug)]
 pub enum LoginState {
     LoggedOut,
     Connected,
-    Authenticated { profile: UserProfile },
-    Error { message: Option<String> },
+    Reconnecting {
+        attempt: u32,
+        next_retry_at: Instant,
+    },
+    Authenticated {
+        profile: UserProfile,
+    },
+    UpdateRequired {
+        minimum: String,
+        download_url: String,
+    },
+    LoginFailed {
+        reason: LoginFailure,
+    },
+    Error {
+        code: ErrorCode,
+    },
 }
 
 pub struct Network {
     login_state: LoginState,
-    sender: Sender<ServerRequest>,
-    receiver: Receiver<ServerRequest>,
+    sender: Sender<RequestEnvelope>,
+    receiver: Receiver<RequestEnvelope>,
+    last_request_id: RequestId,
+    pending_calls: HashMap<RequestId, oneshot::Sender<ServerResponse>>,
+    latency: Option<Duration>,
+    resume_token: Option<Uuid>,
 }
 
 impl Network {
@@ -33,6 +72,10 @@ impl Network {
             login_state: LoginState::LoggedOut,
             sender,
             receiver,
+            last_request_id: 0,
+            pending_calls: HashMap::new(),
+            latency: None,
+            resume_token: None,
         }
     }
 
@@ -50,86 +93,354 @@ impl Network {
         network.login_state.clone()
     }
 
+    async fn set_resume_token(resume_token: Uuid) {
+        let mut network = NETWORK.write().await;
+        network.resume_token = Some(resume_token);
+    }
+
+    async fn resume_token() -> Option<Uuid> {
+        let network = NETWORK.read().await;
+        network.resume_token
+    }
+
+    async fn set_latency(latency: Option<Duration>) {
+        let mut network = NETWORK.write().await;
+        network.latency = latency;
+    }
+
+    /// The most recently measured round-trip time to the server, if connected.
+    pub async fn latency() -> Option<Duration> {
+        let network = NETWORK.read().await;
+        network.latency
+    }
+
     pub async fn request(request: ServerRequest) {
         let network = NETWORK.read().await;
-        network.sender.send(request).unwrap_or_default();
+        network
+            .sender
+            .send(RequestEnvelope { id: None, request })
+            .unwrap_or_default();
+    }
+
+    /// Sends `request` and waits for the first response the server sends for
+    /// it. If no response arrives within `CALL_TIMEOUT`, or the request is
+    /// dropped while offline, an error response is returned instead.
+    pub async fn call(request: ServerRequest) -> ServerResponse {
+        let (id, receiver) = {
+            let mut network = NETWORK.write().await;
+            let id = network.next_request_id();
+            let (sender, receiver) = oneshot::channel();
+            network.pending_calls.insert(id, sender);
+            network
+                .sender
+                .send(RequestEnvelope {
+                    id: Some(id),
+                    request,
+                })
+                .unwrap_or_default();
+            (id, receiver)
+        };
+
+        match tokio::time::timeout(CALL_TIMEOUT, receiver).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => ServerResponse::Error {
+                code: ErrorCode::NotConnected,
+            },
+            Err(_) => {
+                let mut network = NETWORK.write().await;
+                network.pending_calls.remove(&id);
+                ServerResponse::Error {
+                    code: ErrorCode::Timeout,
+                }
+            }
+        }
+    }
+
+    /// Hands `response` to the call waiting on `request_id`, returning false
+    /// if nothing was waiting for it.
+    async fn complete_call(request_id: RequestId, response: &ServerResponse) -> bool {
+        let mut network = NETWORK.write().await;
+        match network.pending_calls.remove(&request_id) {
+            Some(sender) => {
+                sender.send(response.clone()).unwrap_or_default();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Asks the server where to log in and opens that page in the browser.
+    pub async fn log_in() {
+        match Network::call(ServerRequest::AuthenticationUrl).await {
+            ServerResponse::AuthenticateAtUrl { url } => {
+                webbrowser::open(&url).expect("Error launching URL");
+            }
+            ServerResponse::Error { code } => {
+                Network::set_login_state(LoginState::Error { code }).await;
+            }
+            ServerResponse::RateLimited { retry_after } => {
+                warn!(?retry_after, "Login was rate limited");
+            }
+            response => warn!(?response, "Unexpected response to AuthenticationUrl"),
+        }
+    }
+
+    /// Sends `request` with a fresh id, without waiting for a response.
+    async fn request_with_id(request: ServerRequest) -> RequestId {
+        let mut network = NETWORK.write().await;
+        let id = network.next_request_id();
+        network
+            .sender
+            .send(RequestEnvelope {
+                id: Some(id),
+                request,
+            })
+            .unwrap_or_default();
+        id
+    }
+
+    fn next_request_id(&mut self) -> RequestId {
+        self.last_request_id = self.last_request_id.wrapping_add(1);
+        self.last_request_id
     }
 
-    async fn receiver() -> Receiver<ServerRequest> {
+    async fn receiver() -> Receiver<RequestEnvelope> {
         let network = NETWORK.read().await;
         network.receiver.clone()
     }
+
+    /// Removes every request queued while disconnected, returning the ones
+    /// whose `OfflinePolicy` allows them to be sent on the new connection.
+    /// Dropped calls are failed immediately rather than left to time out.
+    async fn take_queued_requests() -> Vec<RequestEnvelope> {
+        let mut network = NETWORK.write().await;
+        let mut replay = Vec::new();
+        while let Ok(envelope) = network.receiver.try_recv() {
+            match OfflinePolicy::for_request(&envelope.request) {
+                OfflinePolicy::Replay => replay.push(envelope),
+                OfflinePolicy::Drop => {
+                    if let Some(id) = envelope.id {
+                        network.pending_calls.remove(&id);
+                    }
+                }
+            }
+        }
+        replay
+    }
+
+    async fn requeue(requests: Vec<RequestEnvelope>) {
+        let network = NETWORK.read().await;
+        for envelope in requests {
+            network.sender.send(envelope).unwrap_or_default();
+        }
+    }
+}
+
+/// What happens to a request that is still queued when a connection is lost.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OfflinePolicy {
+    Replay,
+    Drop,
+}
+
+impl OfflinePolicy {
+    pub fn for_request(request: &ServerRequest) -> Self {
+        match request {
+            // Authenticate is sent at the start of every connection, and
+            // heartbeats only mean something on the connection they were sent on.
+            ServerRequest::Authenticate { .. } | ServerRequest::Ping | ServerRequest::Pong => {
+                OfflinePolicy::Drop
+            }
+            ServerRequest::AuthenticationUrl => OfflinePolicy::Replay,
+        }
+    }
 }
 
 async fn network_loop() {
+    let format = wire_format();
+    let mut attempt = 0;
     loop {
         let socket = match Client::new(&format!(
-            "{}/ws",
-            std::env::var("SERVER_URL").unwrap_or("wss://cantina.khonsu.gg".to_owned())
+            "{}/ws?format={}",
+            std::env::var("SERVER_URL").unwrap_or("wss://cantina.khonsu.gg".to_owned()),
+            format.name()
         ))
         .connect()
         .await
         {
             Ok(socket) => socket,
             Err(err) => {
-                println!("Error connecting to socket. {}", err);
-                tokio::time::delay_for(Duration::from_millis(100)).await;
-                Network::set_login_state(LoginState::Error { message: None }).await;
+                warn!(error = %err, attempt, "Error connecting to the server");
+                attempt += 1;
+                wait_to_reconnect(attempt, Instant::now() + reconnect_delay(attempt)).await;
                 continue;
             }
         };
+        let connected_at = Instant::now();
+        Network::set_login_state(LoginState::Connected).await;
         let (mut tx, mut rx) = socket.into_channel().await;
         let receiver = Network::receiver().await;
         let mut network_limiter = FrequencyLimiter::new(Duration::from_millis(100));
-        Network::request(ServerRequest::Authenticate {
+        let queued_requests = Network::take_queued_requests().await;
+        let authenticate_id = Network::request_with_id(ServerRequest::Authenticate {
             installation_id: UserConfig::installation_id().await,
             version: shared::PROTOCOL_VERSION.to_owned(),
+            resume_token: Network::resume_token().await,
         })
         .await;
+        Network::requeue(queued_requests).await;
 
+        // Until the session starts and the server says otherwise.
+        let mut heartbeat = Heartbeat::new(shared::heartbeat_interval(None));
         loop {
             network_limiter.advance_frame();
-            if receive_loop(&mut rx).await || send_loop(&receiver, &mut tx).await {
+            if receive_loop(format, &mut rx, authenticate_id, &mut heartbeat).await
+                || send_loop(format, &receiver, &mut tx).await
+            {
+                break;
+            }
+
+            if heartbeat.is_dead() {
+                warn!("Server stopped responding");
                 break;
+            } else if heartbeat.should_ping() {
+                let id = Network::request_with_id(ServerRequest::Ping).await;
+                heartbeat.ping_sent(id);
             }
 
             if let Some(sleep_time) = network_limiter.remaining() {
                 tokio::time::delay_for(sleep_time).await;
             }
         }
+        Network::set_latency(None).await;
+
+        if connected_at.elapsed() >= STABLE_CONNECTION_TIME {
+            attempt = 0;
+        }
+        attempt += 1;
+        let next_retry_at = match Network::login_state().await {
+            // The server said when to come back.
+            LoginState::Reconnecting { next_retry_at, .. } => next_retry_at,
+            _ => Instant::now() + reconnect_delay(attempt),
+        };
+        wait_to_reconnect(attempt, next_retry_at).await;
     }
 }
 
-async fn receive_loop(rx: &mut TokioReceiver<Msg>) -> bool {
+async fn wait_to_reconnect(attempt: u32, next_retry_at: Instant) {
+    Network::set_login_state(LoginState::Reconnecting {
+        attempt,
+        next_retry_at,
+    })
+    .await;
+    tokio::time::delay_for(next_retry_at.saturating_duration_since(Instant::now())).await;
+}
+
+/// Capped exponential backoff with "equal jitter": the delay is randomly
+/// chosen between half and all of the exponential delay, so clients that lost
+/// their connection at the same time don't all retry at once.
+fn reconnect_delay(attempt: u32) -> Duration {
+    let exponential = INITIAL_RECONNECT_DELAY
+        .checked_mul(1 << attempt.saturating_sub(1).min(16))
+        .unwrap_or(MAX_RECONNECT_DELAY)
+        .min(MAX_RECONNECT_DELAY);
+    let max_ms = exponential.as_millis() as u64;
+    Duration::from_millis(rand::thread_rng().gen_range(max_ms / 2, max_ms + 1))
+}
+
+/// Set `WIRE_FORMAT=json` to make traffic readable while debugging.
+fn wire_format() -> WireFormat {
+    std::env::var("WIRE_FORMAT")
+        .ok()
+        .and_then(|format| format.parse().ok())
+        .unwrap_or(WireFormat::BincodeDeflate)
+}
+
+/// Pings once per interval whether or not the last ping was answered, so a
+/// lost or rate limited `Pong` only costs one latency measurement. Only the
+/// `Pong` for the latest ping is measured.
+struct Heartbeat {
+    interval: Duration,
+    last_received: Instant,
+    last_ping_sent: Option<Instant>,
+    unanswered_ping: Option<RequestId>,
+}
+
+impl Heartbeat {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_received: Instant::now(),
+            last_ping_sent: None,
+            unanswered_ping: None,
+        }
+    }
+
+    fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    fn received(&mut self) {
+        self.last_received = Instant::now();
+    }
+
+    fn ping_sent(&mut self, id: RequestId) {
+        self.last_ping_sent = Some(Instant::now());
+        self.unanswered_ping = Some(id);
+    }
+
+    fn pong_received(&mut self, request_id: Option<RequestId>) -> Option<Duration> {
+        if request_id.is_some() && request_id == self.unanswered_ping {
+            self.unanswered_ping = None;
+            self.last_ping_sent.map(|sent| sent.elapsed())
+        } else {
+            None
+        }
+    }
+
+    fn should_ping(&self) -> bool {
+        self.last_ping_sent
+            .map(|sent| sent.elapsed() >= self.interval)
+            .unwrap_or(true)
+    }
+
+    fn is_dead(&self) -> bool {
+        self.last_received.elapsed() > shared::heartbeat_timeout(self.interval)
+    }
+}
+
+async fn receive_loop(
+    format: WireFormat,
+    rx: &mut TokioReceiver<Msg>,
+    authenticate_id: RequestId,
+    heartbeat: &mut Heartbeat,
+) -> bool {
     loop {
         match rx.try_recv() {
-            Ok(msg) => match msg {
-                Msg::Binary(bytes) => match bincode::deserialize::<ServerResponse>(&bytes) {
-                    Ok(response) => match response {
-                        ServerResponse::Error { message } => {
-                            Network::set_login_state(LoginState::Error { message }).await;
-                        }
-                        ServerResponse::AdoptInstallationId { installation_id } => {
-                            println!("Received app token {}", installation_id);
-                            UserConfig::set_installation_id(installation_id).await;
-                            Network::set_login_state(LoginState::Connected).await;
+            Ok(msg) => {
+                let decoded = match msg {
+                    Msg::Binary(bytes) => format.decode::<ResponseEnvelope>(&bytes),
+                    Msg::Text(text) => format.decode::<ResponseEnvelope>(text.as_bytes()),
+                    _ => continue,
+                };
+                match decoded {
+                    Ok(envelope) => {
+                        heartbeat.received();
+                        if let Some(request_id) = envelope.request_id {
+                            if Network::complete_call(request_id, &envelope.response).await {
+                                continue;
+                            }
                         }
-                        ServerResponse::Authenticated { profile } => {
-                            println!("Authenticated as {}", profile.username);
-                            Network::set_login_state(LoginState::Authenticated { profile }).await;
-                        }
-
-                        ServerResponse::AuthenticateAtUrl { url } => {
-                            webbrowser::open(&url).expect("Error launching URL");
+                        if handle_response(envelope, authenticate_id, heartbeat).await {
+                            return true;
                         }
-                    },
-                    Err(_) => println!("Error deserializing message."),
-                },
-                _ => {}
-            },
+                    }
+                    Err(err) => error!(error = %err, "Error decoding response"),
+                }
+            }
             Err(err) => match err {
                 TokioTryRecvError::Closed => {
-                    println!("Socket Disconnected");
+                    info!("Disconnected");
                     return true;
                 }
                 _ => return false,
@@ -138,19 +449,104 @@ async fn receive_loop(rx: &mut TokioReceiver<Msg>) -> bool {
     }
 }
 
-async fn send_loop(receiver: &Receiver<ServerRequest>, tx: &mut TokioSender<Msg>) -> bool {
+/// Returns true if the connection should be closed.
+async fn handle_response(
+    envelope: ResponseEnvelope,
+    authenticate_id: RequestId,
+    heartbeat: &mut Heartbeat,
+) -> bool {
+    match envelope.response {
+        ServerResponse::Error { code } => {
+            Network::set_login_state(LoginState::Error { code }).await;
+        }
+        ServerResponse::AdoptInstallationId { installation_id } => {
+            info!("Received a new installation id");
+            UserConfig::set_installation_id(installation_id).await;
+            Network::set_login_state(LoginState::Connected).await;
+        }
+        ServerResponse::Authenticated { profile } => {
+            info!(username = %profile.username, "Authenticated");
+            Network::set_login_state(LoginState::Authenticated { profile }).await;
+        }
+        ServerResponse::LoginFailed { reason } => {
+            Network::set_login_state(LoginState::LoginFailed { reason }).await;
+        }
+
+        ServerResponse::SessionStarted {
+            resume_token,
+            heartbeat_interval,
+        } => {
+            Network::set_resume_token(resume_token).await;
+            heartbeat.set_interval(heartbeat_interval);
+        }
+        ServerResponse::SessionResumed { heartbeat_interval } => {
+            info!("Resumed previous session");
+            heartbeat.set_interval(heartbeat_interval);
+        }
+        ServerResponse::UpdateRequired {
+            minimum,
+            download_url,
+        } => {
+            Network::set_login_state(LoginState::UpdateRequired {
+                minimum,
+                download_url,
+            })
+            .await;
+        }
+        // Only sent in reply to `Network::log_in`, which has given up on it
+        // if it ends up here.
+        ServerResponse::AuthenticateAtUrl { .. } => {}
+        ServerResponse::RateLimited { retry_after } => {
+            warn!(?retry_after, "Request was rate limited");
+            // The connection is useless until it authenticates, so try again
+            // on a new one once the server allows it.
+            if envelope.request_id == Some(authenticate_id) {
+                Network::set_login_state(LoginState::Reconnecting {
+                    attempt: 1,
+                    next_retry_at: Instant::now() + retry_after,
+                })
+                .await;
+                return true;
+            }
+        }
+        ServerResponse::ServerShuttingDown { reconnect_after } => {
+            info!(?reconnect_after, "Server is shutting down");
+            Network::set_login_state(LoginState::Reconnecting {
+                attempt: 1,
+                next_retry_at: Instant::now() + reconnect_after + reconnect_delay(1),
+            })
+            .await;
+            return true;
+        }
+        ServerResponse::Ping => {
+            Network::request(ServerRequest::Pong).await;
+        }
+        ServerResponse::Pong => {
+            if let Some(latency) = heartbeat.pong_received(envelope.request_id) {
+                Network::set_latency(Some(latency)).await;
+            }
+        }
+    }
+    false
+}
+
+async fn send_loop(
+    format: WireFormat,
+    receiver: &Receiver<RequestEnvelope>,
+    tx: &mut TokioSender<Msg>,
+) -> bool {
     loop {
         match receiver.try_recv() {
             Ok(request) => {
-                match tx
-                    .send(Msg::Binary(bincode::serialize(&request).unwrap()))
-                    .await
-                {
-                    Err(err) => {
-                        println!("Error sending message: {}", err);
-                        return true;
-                    }
-                    _ => {}
+                let bytes = format.encode(&request).expect("Error encoding request");
+                let msg = if format.is_text() {
+                    Msg::Text(String::from_utf8(bytes).expect("Text formats produce UTF-8"))
+                } else {
+                    Msg::Binary(bytes)
+                };
+                if let Err(err) = tx.send(msg).await {
+                    warn!(error = %err, "Error sending request");
+                    return true;
                 }
             }
             Err(err) => match err {
@@ -160,3 +556,43 @@ async fn send_loop(receiver: &Receiver<ServerRequest>, tx: &mut TokioSender<Msg>
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeat_survives_lost_pongs() {
+        let mut heartbeat = Heartbeat::new(Duration::from_millis(10));
+        assert!(heartbeat.should_ping());
+        heartbeat.ping_sent(1);
+        assert!(!heartbeat.should_ping());
+
+        // The first pong never arrives, but the next interval pings anyway.
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(heartbeat.should_ping());
+        heartbeat.ping_sent(2);
+
+        // A late pong for the lost ping would overstate the latency.
+        assert_eq!(heartbeat.pong_received(Some(1)), None);
+        assert_eq!(heartbeat.pong_received(None), None);
+        assert!(heartbeat.pong_received(Some(2)).is_some());
+        assert_eq!(heartbeat.pong_received(Some(2)), None);
+    }
+
+    #[test]
+    fn reconnect_delay_backs_off_to_the_cap() {
+        for _ in 0..100 {
+            let first = reconnect_delay(1);
+            assert!(first >= INITIAL_RECONNECT_DELAY / 2 && first <= INITIAL_RECONNECT_DELAY);
+
+            let third = reconnect_delay(3);
+            assert!(third >= INITIAL_RECONNECT_DELAY * 2 && third <= INITIAL_RECONNECT_DELAY * 4);
+
+            for &attempt in &[8, 17, 64, u32::MAX] {
+                let capped = reconnect_delay(attempt);
+                assert!(capped >= MAX_RECONNECT_DELAY / 2 && capped <= MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
 mod migrations;
 
-pub use self::migrations::{pg, run_all};
+pub use self::migrations::{configure_pool, migrations, pg, run_all};
 pub use sqlx;
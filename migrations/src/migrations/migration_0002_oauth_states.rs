@@ -0,0 +1,54 @@
+use sqlx_simple_migrator::Migration;
+
+pub fn migration() -> Migration {
+    Migration::new(std::file!())
+        .with_up(
+            r#"
+        CREATE TABLE oauth_states (
+            state TEXT PRIMARY KEY,
+            installation_id UUID NOT NULL REFERENCES installations(id) ON DELETE CASCADE,
+            expires_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+        )
+        .with_down(
+            r#"
+        DROP TABLE IF EXISTS oauth_states
+        "#,
+        )
+        .with_up(
+            r#"
+        CREATE FUNCTION oauth_state_create(state_in TEXT, installation_id_in UUID, lifetime_secs INT) RETURNS TEXT AS $$ 
+            BEGIN
+                DELETE FROM oauth_states WHERE expires_at <= now();
+                INSERT INTO oauth_states (state, installation_id, expires_at)
+                    VALUES (state_in, installation_id_in, now() + lifetime_secs * interval '1 second');
+                RETURN state_in;
+            END;
+            $$ LANGUAGE plpgsql;
+        "#,
+        )
+        .with_down(
+            r#"
+        DROP FUNCTION IF EXISTS oauth_state_create
+        "#,
+        )
+        .with_up(
+            r#"
+        CREATE FUNCTION oauth_state_consume(state_in TEXT) RETURNS UUID AS $$ 
+            DECLARE
+                consumed_installation_id UUID;
+            BEGIN
+                DELETE FROM oauth_states WHERE state = state_in AND expires_at > now()
+                    RETURNING installation_id INTO consumed_installation_id;
+                RETURN consumed_installation_id;
+            END;
+            $$ LANGUAGE plpgsql;
+        "#,
+        )
+        .with_down(
+            r#"
+        DROP FUNCTION IF EXISTS oauth_state_consume
+        "#,
+        )
+}
@@ -0,0 +1,179 @@
+use sqlx_simple_migrator::Migration;
+
+pub fn migration() -> Migration {
+    Migration::new(std::file!())
+        .with_up(
+            r#"
+        CREATE TABLE account_identities (
+            provider TEXT NOT NULL,
+            external_id TEXT NOT NULL,
+            account_id BIGINT NOT NULL REFERENCES accounts(id) ON DELETE CASCADE,
+            token TEXT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            PRIMARY KEY (provider, external_id)
+        )
+        "#,
+        )
+        .with_down(
+            r#"
+        DROP TABLE IF EXISTS account_identities
+        "#,
+        )
+        .with_up(
+            r#"
+        INSERT INTO account_identities (provider, external_id, account_id, token, created_at)
+            SELECT 'itchio', itchio_user_id::text, id, itchio_token, created_at FROM accounts
+        "#,
+        )
+        .with_down(
+            r#"
+        UPDATE accounts SET itchio_user_id = account_identities.external_id::bigint, itchio_token = account_identities.token
+            FROM account_identities
+            WHERE account_identities.account_id = accounts.id AND account_identities.provider = 'itchio'
+        "#,
+        )
+        .with_up(
+            r#"
+        DROP FUNCTION account_lookup(BIGINT, TEXT)
+        "#,
+        )
+        .with_down(
+            r#"
+        CREATE FUNCTION account_lookup(itchio_user_id_in BIGINT, username_in TEXT) RETURNS BIGINT AS $$
+            DECLARE
+                new_account_id BIGINT NOT NULL := 0;
+            BEGIN
+                INSERT INTO accounts (itchio_user_id, username) VALUES (itchio_user_id_in, username_in)
+                    ON CONFLICT (itchio_user_id) DO UPDATE SET username = username_in
+                    RETURNING id INTO new_account_id;
+                RETURN new_account_id;
+            END;
+            $$ LANGUAGE plpgsql;
+        "#,
+        )
+        .with_up(
+            r#"
+        DROP FUNCTION account_get_itchio_token(BIGINT)
+        "#,
+        )
+        .with_down(
+            r#"
+        CREATE FUNCTION account_get_itchio_token(id_in BIGINT) RETURNS TEXT AS $$
+            DECLARE
+                token TEXT;
+            BEGIN
+                token := (SELECT itchio_token FROM accounts WHERE id = id_in);
+                RETURN token;
+            END;
+            $$ LANGUAGE plpgsql;
+        "#,
+        )
+        .with_up(
+            r#"
+        DROP FUNCTION installation_login(UUID, BIGINT, TEXT)
+        "#,
+        )
+        .with_down(
+            r#"
+        CREATE FUNCTION installation_login(installation_id UUID, account_id_in BIGINT, itchio_token_in TEXT) RETURNS bigint AS $$
+            DECLARE
+                affected_rows bigint;
+            BEGIN
+                UPDATE accounts SET itchio_token = itchio_token_in WHERE accounts.id = account_id_in;
+                UPDATE installations SET account_id = account_id_in WHERE id = installation_id;
+                GET DIAGNOSTICS affected_rows = ROW_COUNT;
+                PERFORM pg_notify('installation_login', installation_id::text);
+                RETURN affected_rows;
+            END;
+            $$ LANGUAGE plpgsql;
+        "#,
+        )
+        .with_up(
+            r#"
+        ALTER TABLE accounts
+            DROP COLUMN itchio_user_id,
+            DROP COLUMN itchio_token
+        "#,
+        )
+        .with_down(
+            r#"
+        ALTER TABLE accounts
+            ADD COLUMN itchio_user_id BIGINT NULL UNIQUE,
+            ADD COLUMN itchio_token TEXT NULL
+        "#,
+        )
+        .with_up(
+            r#"
+        CREATE FUNCTION account_lookup(provider_in TEXT, external_id_in TEXT, username_in TEXT, token_in TEXT) RETURNS BIGINT AS $$
+            DECLARE
+                new_account_id BIGINT;
+                found_account_id BIGINT;
+            BEGIN
+                UPDATE account_identities SET token = token_in
+                    WHERE provider = provider_in AND external_id = external_id_in
+                    RETURNING account_id INTO found_account_id;
+                IF found_account_id IS NULL THEN
+                    -- Create an account for the identity, then claim it. If a concurrent
+                    -- login claimed it first, ON CONFLICT waits for it and returns its
+                    -- account, and the account created here is thrown away.
+                    new_account_id := nextval(pg_get_serial_sequence('accounts', 'id'));
+                    INSERT INTO accounts (id, username) VALUES (new_account_id, '#' || new_account_id);
+                    INSERT INTO account_identities (provider, external_id, account_id, token)
+                        VALUES (provider_in, external_id_in, new_account_id, token_in)
+                        ON CONFLICT (provider, external_id) DO UPDATE SET token = token_in
+                        RETURNING account_id INTO found_account_id;
+                    IF found_account_id <> new_account_id THEN
+                        DELETE FROM accounts WHERE id = new_account_id;
+                    END IF;
+                END IF;
+                -- Usernames are unique, but two providers can each have a user with the
+                -- same name. Whoever has the name keeps it; anyone else gets it suffixed
+                -- with their account id.
+                BEGIN
+                    UPDATE accounts SET username = username_in WHERE id = found_account_id;
+                EXCEPTION WHEN unique_violation THEN
+                    UPDATE accounts SET username = username_in || '#' || found_account_id
+                        WHERE id = found_account_id;
+                END;
+                RETURN found_account_id;
+            END;
+            $$ LANGUAGE plpgsql;
+        "#,
+        )
+        .with_down(
+            r#"
+        DROP FUNCTION IF EXISTS account_lookup(TEXT, TEXT, TEXT, TEXT)
+        "#,
+        )
+        .with_up(
+            r#"
+        CREATE FUNCTION account_get_token(account_id_in BIGINT, provider_in TEXT) RETURNS TEXT AS $$
+            SELECT token FROM account_identities WHERE account_id = account_id_in AND provider = provider_in;
+            $$ LANGUAGE sql;
+        "#,
+        )
+        .with_down(
+            r#"
+        DROP FUNCTION IF EXISTS account_get_token
+        "#,
+        )
+        .with_up(
+            r#"
+        CREATE FUNCTION installation_login(installation_id UUID, account_id_in BIGINT) RETURNS bigint AS $$
+            DECLARE
+                affected_rows bigint;
+            BEGIN
+                UPDATE installations SET account_id = account_id_in WHERE id = installation_id;
+                GET DIAGNOSTICS affected_rows = ROW_COUNT;
+                PERFORM pg_notify('installation_login', installation_id::text);
+                RETURN affected_rows;
+            END;
+            $$ LANGUAGE plpgsql;
+        "#,
+        )
+        .with_down(
+            r#"
+        DROP FUNCTION IF EXISTS installation_login(UUID, BIGINT)
+        "#,
+        )
+}
@@ -1,22 +1,42 @@
 mod migration_0001_accounts;
+mod migration_0002_oauth_states;
+mod migration_0003_account_identities;
 use futures::executor::block_on;
 use lazy_static::lazy_static;
 use sqlx_simple_migrator::{Migration, MigrationError};
-use std::env;
+use std::{env, sync::Mutex};
 
 pub fn migrations() -> Vec<Migration> {
-    vec![migration_0001_accounts::migration()]
+    vec![
+        migration_0001_accounts::migration(),
+        migration_0002_oauth_states::migration(),
+        migration_0003_account_identities::migration(),
+    ]
 }
 
 use sqlx::PgPool;
 
 lazy_static! {
     static ref POOL: PgPool = {
-        block_on(PgPool::new(
-            &env::var("DATABASE_URL").expect("DATABASE_URL not set"),
-        ))
-        .expect("Error initializing postgres pool")
+        let (url, max_size) = POOL_SETTINGS.lock().unwrap().take().unwrap_or_else(|| {
+            (
+                env::var("DATABASE_URL").expect("DATABASE_URL not set"),
+                DEFAULT_POOL_SIZE,
+            )
+        });
+        block_on(PgPool::builder().max_size(max_size).build(&url))
+            .expect("Error initializing postgres pool")
     };
+    static ref POOL_SETTINGS: Mutex<Option<(String, u32)>> = Mutex::new(None);
+}
+
+const DEFAULT_POOL_SIZE: u32 = 10;
+
+/// Sets the connection string and size of the pool returned by `pg()`. Has no
+/// effect once `pg()` has been called; until then, `pg()` falls back to
+/// `DATABASE_URL`.
+pub fn configure_pool(url: &str, max_size: u32) {
+    *POOL_SETTINGS.lock().unwrap() = Some((url.to_owned(), max_size));
 }
 
 pub fn pg() -> PgPool {
@@ -53,39 +73,136 @@ mod tests {
         assert_eq!(None, installation.account_id);
 
         // Simulate looking up an account for a user
-        let account = sqlx::query!("SELECT account_lookup($1, $2) as account_id", 1, "username")
-            .fetch_one(&mut tx)
-            .await
-            .expect("Function should always return a value");
-
-        let repeated_account_lookup =
-            sqlx::query!("SELECT account_lookup($1, $2) as account_id", 1, "username")
-                .fetch_one(&mut tx)
-                .await
-                .expect("Function should always return a value");
+        let account = sqlx::query!(
+            "SELECT account_lookup($1, $2, $3, $4) as account_id",
+            "itchio",
+            "1",
+            "username",
+            "itchio_token"
+        )
+        .fetch_one(&mut tx)
+        .await
+        .expect("Function should always return a value");
+
+        let repeated_account_lookup = sqlx::query!(
+            "SELECT account_lookup($1, $2, $3, $4) as account_id",
+            "itchio",
+            "1",
+            "username",
+            "refreshed_token"
+        )
+        .fetch_one(&mut tx)
+        .await
+        .expect("Function should always return a value");
         assert_eq!(repeated_account_lookup.account_id, account.account_id);
 
+        // The same id and username from another provider is someone else
+        let other_provider_account = sqlx::query!(
+            "SELECT account_lookup($1, $2, $3, $4) as account_id",
+            "dev",
+            "1",
+            "username",
+            "dev_token"
+        )
+        .fetch_one(&mut tx)
+        .await
+        .expect("Function should always return a value");
+        assert_ne!(other_provider_account.account_id, account.account_id);
+
+        // Usernames stay unique, so the second account's name is disambiguated
+        let usernames = sqlx::query!(
+            "SELECT id, username FROM accounts WHERE id = $1 OR id = $2 ORDER BY id",
+            account.account_id,
+            other_provider_account.account_id
+        )
+        .fetch_all(&mut tx)
+        .await?;
+        assert_eq!(usernames[0].username, "username");
+        assert_eq!(
+            usernames[1].username,
+            format!("username#{}", usernames[1].id)
+        );
+
         // Assign the installation to the account
         let installation_set_account_result = sqlx::query!(
-            "SELECT installation_login($1, $2, $3) as rows_changed",
+            "SELECT installation_login($1, $2) as rows_changed",
             installation_id,
-            account.account_id,
-            "itchio_token"
+            account.account_id
         )
         .fetch_one(&mut tx)
         .await?;
         assert_eq!(installation_set_account_result.rows_changed, Some(1));
 
-        // Check that the token is present when looking it up
+        // Check that the latest token is present when looking it up
         let account_token_result = sqlx::query!(
-            "SELECT account_get_itchio_token($1) as token",
-            account.account_id
+            "SELECT account_get_token($1, $2) as token",
+            account.account_id,
+            "itchio"
         )
         .fetch_one(&mut tx)
         .await?;
-        assert_eq!(account_token_result.token, Some("itchio_token".to_owned()));
+        assert_eq!(
+            account_token_result.token,
+            Some("refreshed_token".to_owned())
+        );
 
         // transaction is automatically rolled back
         Ok(())
     }
+
+    #[tokio::test]
+    async fn oauth_states_test() -> Result<(), sqlx::Error> {
+        dotenv::dotenv().unwrap();
+        let pool = pg();
+        let mut tx = pool.begin().await?;
+
+        let installation_id = Uuid::new_v4();
+        sqlx::query!("SELECT * FROM installation_lookup($1)", installation_id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        // A state can be consumed exactly once
+        sqlx::query!(
+            "SELECT oauth_state_create($1, $2, $3) as state",
+            "fresh",
+            installation_id,
+            600
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        let consumed = sqlx::query!("SELECT oauth_state_consume($1) as installation_id", "fresh")
+            .fetch_one(&mut tx)
+            .await?;
+        assert_eq!(consumed.installation_id, Some(installation_id));
+        let replayed = sqlx::query!("SELECT oauth_state_consume($1) as installation_id", "fresh")
+            .fetch_one(&mut tx)
+            .await?;
+        assert_eq!(replayed.installation_id, None);
+
+        // Expired and unknown states are rejected
+        sqlx::query!(
+            "SELECT oauth_state_create($1, $2, $3) as state",
+            "expired",
+            installation_id,
+            0
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        let expired = sqlx::query!(
+            "SELECT oauth_state_consume($1) as installation_id",
+            "expired"
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        assert_eq!(expired.installation_id, None);
+        let unknown = sqlx::query!(
+            "SELECT oauth_state_consume($1) as installation_id",
+            "unknown"
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        assert_eq!(unknown.installation_id, None);
+
+        Ok(())
+    }
 }
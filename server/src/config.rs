@@ -0,0 +1,519 @@
+use crate::outbox::OutboxSettings;
+use crate::proxies::TrustedProxies;
+use crate::rate_limit::{RateLimits, REQUEST_KINDS};
+use anyhow::Context;
+use lazy_static::lazy_static;
+use serde_derive::Deserialize;
+use std::{fmt::Display, net::SocketAddr, str::FromStr, time::Duration};
+use tracing_subscriber::EnvFilter;
+use url::Url;
+
+lazy_static! {
+    pub static ref CONFIG: ServerConfig = match ServerConfig::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{:#}", err);
+            std::process::exit(1);
+        }
+    };
+}
+
+const DEFAULT_CONFIG_FILE: &str = "server.toml";
+
+/// The server's settings, read from a TOML file (`CONFIG_FILE`, or
+/// `server.toml` if it exists) and then overridden by environment variables.
+/// See `server.example.toml` for every setting.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: SocketAddr,
+    /// Where `/metrics` is served, apart from the public routes.
+    pub metrics_bind_address: SocketAddr,
+    /// The URL players reach this server at, which OAuth redirects are
+    /// built from.
+    pub public_url: Url,
+    pub client_download_url: Url,
+    /// The load balancers and proxies whose `X-Forwarded-For` is believed.
+    pub trusted_proxies: TrustedProxies,
+    pub heartbeat_interval_ms: u64,
+    pub session_grace_period_secs: u64,
+    pub database: DatabaseConfig,
+    pub oauth: OAuthConfig,
+    pub outbox: OutboxSettings,
+    pub rate_limits: RateLimits,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub pool_size: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OAuthConfig {
+    pub client_id: String,
+    /// Only used by the `code` flow.
+    pub client_secret: String,
+    pub flow: OAuthFlow,
+    /// Where itch.io's login page and API are.
+    pub itchio_url: Url,
+    /// How long a player has to finish logging in at itch.io.
+    pub state_lifetime_secs: u32,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuthFlow {
+    /// itch.io hands the access token to `logged_in.html` in the URL
+    /// fragment, which posts it to `/auth/receive_token`.
+    Implicit,
+    /// itch.io redirects back with a code, which the server exchanges for the
+    /// access token using `client_secret`. itch.io only documents the
+    /// implicit flow, so this hasn't been tried against the real site.
+    Code,
+}
+
+impl FromStr for OAuthFlow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "implicit" => Ok(OAuthFlow::Implicit),
+            "code" => Ok(OAuthFlow::Code),
+            _ => Err(format!(
+                "Invalid OAuth flow `{}`, expected `implicit` or `code`",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Which events to log, as `tracing_subscriber::EnvFilter` directives,
+    /// e.g. `info` or `warn,server=debug`.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_owned(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "Invalid log format `{}`, expected `text` or `json`",
+                s
+            )),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: ([0, 0, 0, 0], 7878).into(),
+            metrics_bind_address: ([0, 0, 0, 0], 9100).into(),
+            public_url: Url::parse("http://localhost:7878/").unwrap(),
+            client_download_url: Url::parse("https://cantina.khonsu.gg/").unwrap(),
+            trusted_proxies: TrustedProxies::default(),
+            heartbeat_interval_ms: shared::DEFAULT_HEARTBEAT_INTERVAL_MS,
+            session_grace_period_secs: 30,
+            database: DatabaseConfig::default(),
+            oauth: OAuthConfig::default(),
+            outbox: OutboxSettings::default(),
+            rate_limits: RateLimits::default(),
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+impl Default for OAuthConfig {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            flow: OAuthFlow::Implicit,
+            itchio_url: Url::parse("https://itch.io/").unwrap(),
+            state_lifetime_secs: 600,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            pool_size: 10,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load() -> Result<Self, anyhow::Error> {
+        let mut config = match std::env::var("CONFIG_FILE") {
+            Ok(path) => {
+                let contents = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Error reading config file {}", path))?;
+                Self::from_toml(&contents).with_context(|| format!("Error in {}", path))?
+            }
+            Err(_) => match std::fs::read_to_string(DEFAULT_CONFIG_FILE) {
+                Ok(contents) => Self::from_toml(&contents)
+                    .with_context(|| format!("Error in {}", DEFAULT_CONFIG_FILE))?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::default(),
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("Error reading {}", DEFAULT_CONFIG_FILE))
+                }
+            },
+        };
+
+        let mut problems = config.apply_overrides(|name| std::env::var(name).ok());
+        config.normalize();
+        problems.extend(config.validate());
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            anyhow::bail!(
+                "Invalid server configuration:\n  - {}",
+                problems.join("\n  - ")
+            )
+        }
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self, anyhow::Error> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// Applies environment variable overrides, read through `var`. Returns a
+    /// description of each variable that couldn't be parsed.
+    fn apply_overrides<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Vec<String> {
+        let mut problems = Vec::new();
+        override_with(&var, "BIND_ADDRESS", &mut self.bind_address, &mut problems);
+        override_with(
+            &var,
+            "METRICS_BIND_ADDRESS",
+            &mut self.metrics_bind_address,
+            &mut problems,
+        );
+        override_with(&var, "PUBLIC_URL", &mut self.public_url, &mut problems);
+        override_with(
+            &var,
+            "CLIENT_DOWNLOAD_URL",
+            &mut self.client_download_url,
+            &mut problems,
+        );
+        override_with(
+            &var,
+            "TRUSTED_PROXIES",
+            &mut self.trusted_proxies,
+            &mut problems,
+        );
+        override_with(
+            &var,
+            "HEARTBEAT_INTERVAL_MS",
+            &mut self.heartbeat_interval_ms,
+            &mut problems,
+        );
+        override_with(
+            &var,
+            "SESSION_GRACE_PERIOD_SECS",
+            &mut self.session_grace_period_secs,
+            &mut problems,
+        );
+        override_with(&var, "DATABASE_URL", &mut self.database.url, &mut problems);
+        override_with(
+            &var,
+            "DATABASE_POOL_SIZE",
+            &mut self.database.pool_size,
+            &mut problems,
+        );
+        override_with(
+            &var,
+            "OAUTH_CLIENT_ID",
+            &mut self.oauth.client_id,
+            &mut problems,
+        );
+        override_with(
+            &var,
+            "OAUTH_CLIENT_SECRET",
+            &mut self.oauth.client_secret,
+            &mut problems,
+        );
+        override_with(&var, "OAUTH_FLOW", &mut self.oauth.flow, &mut problems);
+        override_with(
+            &var,
+            "ITCHIO_URL",
+            &mut self.oauth.itchio_url,
+            &mut problems,
+        );
+        override_with(
+            &var,
+            "OAUTH_STATE_LIFETIME_SECS",
+            &mut self.oauth.state_lifetime_secs,
+            &mut problems,
+        );
+        override_with(
+            &var,
+            "OUTBOX_CAPACITY",
+            &mut self.outbox.capacity,
+            &mut problems,
+        );
+        override_with(
+            &var,
+            "OUTBOX_OVERFLOW_POLICY",
+            &mut self.outbox.policy,
+            &mut problems,
+        );
+        override_with(&var, "LOG_LEVEL", &mut self.logging.level, &mut problems);
+        override_with(&var, "LOG_FORMAT", &mut self.logging.format, &mut problems);
+        for kind in REQUEST_KINDS.iter() {
+            let name = format!("RATE_LIMIT_{}", kind.to_uppercase());
+            if let Some(value) = var(&name) {
+                match value.parse() {
+                    Ok(limit) => self.rate_limits.set(kind, limit).unwrap(),
+                    Err(err) => problems.push(format!("{}: {}", name, err)),
+                }
+            }
+        }
+        problems
+    }
+
+    /// Makes the base URLs directories, so joining a path onto them appends
+    /// to their path rather than replacing the last segment, and fills in the
+    /// defaults that depend on other settings.
+    fn normalize(&mut self) {
+        self.rate_limits.limit_heartbeats(self.heartbeat_interval());
+        for url in &mut [&mut self.public_url, &mut self.oauth.itchio_url] {
+            if !url.path().ends_with('/') {
+                let path = format!("{}/", url.path());
+                url.set_path(&path);
+            }
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (name, url) in &[
+            ("public_url", &self.public_url),
+            ("oauth.itchio_url", &self.oauth.itchio_url),
+        ] {
+            if url.cannot_be_a_base() || !["http", "https"].contains(&url.scheme()) {
+                problems.push(format!("{} `{}` must be an http or https URL", name, url));
+            }
+        }
+        if self.metrics_bind_address == self.bind_address {
+            problems.push("metrics_bind_address must differ from bind_address".to_owned());
+        }
+        if self.database.url.is_empty() {
+            problems.push("database.url (DATABASE_URL) is required".to_owned());
+        }
+        if self.database.pool_size == 0 {
+            problems.push("database.pool_size must be at least 1".to_owned());
+        }
+        if self.oauth.client_id.is_empty() {
+            problems.push("oauth.client_id (OAUTH_CLIENT_ID) is required".to_owned());
+        }
+        if self.oauth.flow == OAuthFlow::Code && self.oauth.client_secret.is_empty() {
+            problems.push(
+                "oauth.client_secret (OAUTH_CLIENT_SECRET) is required by the code flow".to_owned(),
+            );
+        }
+        if self.oauth.state_lifetime_secs == 0 {
+            problems.push("oauth.state_lifetime_secs must be at least 1".to_owned());
+        }
+        if self.heartbeat_interval_ms == 0 {
+            problems.push("heartbeat_interval_ms must be at least 1".to_owned());
+        }
+        if self.session_grace_period_secs == 0 {
+            problems.push("session_grace_period_secs must be at least 1".to_owned());
+        }
+        if self.outbox.capacity == 0 {
+            problems.push("outbox.capacity must be at least 1".to_owned());
+        }
+        if let Err(err) = EnvFilter::try_new(&self.logging.level) {
+            problems.push(format!(
+                "logging.level `{}` is invalid: {}",
+                self.logging.level, err
+            ));
+        }
+        problems
+    }
+
+    /// The public URL of `path`, which is relative to `public_url`.
+    pub fn public_url_for(&self, path: &str) -> Url {
+        self.public_url
+            .join(path)
+            .expect("Paths are always valid relative to an http URL")
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        shared::heartbeat_interval(Some(self.heartbeat_interval_ms))
+    }
+
+    pub fn session_grace_period(&self) -> Duration {
+        Duration::from_secs(self.session_grace_period_secs)
+    }
+}
+
+fn override_with<F, T>(var: &F, name: &str, setting: &mut T, problems: &mut Vec<String>)
+where
+    F: Fn(&str) -> Option<String>,
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = var(name) {
+        match value.parse() {
+            Ok(value) => *setting = value,
+            Err(err) => problems.push(format!("{} `{}`: {}", name, value, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbox::OverflowPolicy;
+    use crate::rate_limit::RateLimit;
+    use std::collections::HashMap;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn parses_toml() {
+        let mut config = ServerConfig::from_toml(
+            r#"
+            bind_address = "127.0.0.1:8000"
+            public_url = "https://cantina.example.com/"
+            heartbeat_interval_ms = 1000
+
+            [database]
+            url = "postgres://localhost/cantina"
+            pool_size = 4
+
+            [oauth]
+            client_id = "abc"
+
+            [outbox]
+            overflow_policy = "disconnect"
+
+            [rate_limits]
+            authenticate = "3/10"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.bind_address, ([127, 0, 0, 1], 8000).into());
+        assert_eq!(config.public_url.as_str(), "https://cantina.example.com/");
+        assert_eq!(config.database.pool_size, 4);
+        assert_eq!(config.outbox.policy, OverflowPolicy::Disconnect);
+        assert_eq!(config.outbox.capacity, OutboxSettings::default().capacity);
+        assert_eq!(
+            config.rate_limits.get("authenticate"),
+            Some(RateLimit::new(3, Duration::from_secs(10)))
+        );
+        // Unspecified limits keep their defaults.
+        assert_eq!(
+            config.rate_limits.get("authentication_url"),
+            RateLimits::default().get("authentication_url")
+        );
+        // Heartbeat limits follow the heartbeat interval.
+        config.normalize();
+        assert_eq!(
+            config.rate_limits.get("ping"),
+            Some(RateLimit::for_heartbeats(Duration::from_secs(1)))
+        );
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn example_config_parses() {
+        ServerConfig::from_toml(include_str!("../server.example.toml")).unwrap();
+    }
+
+    #[test]
+    fn public_urls_are_relative_to_public_url() {
+        let mut config = ServerConfig {
+            public_url: Url::parse("https://example.com/cantina").unwrap(),
+            ..ServerConfig::default()
+        };
+        config.normalize();
+        assert_eq!(
+            config.public_url_for("auth/itchio_callback").as_str(),
+            "https://example.com/cantina/auth/itchio_callback"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(ServerConfig::from_toml("bind_adress = \"0.0.0.0:80\"").is_err());
+        assert!(ServerConfig::from_toml("[rate_limits]\nlogin = \"1/1\"").is_err());
+    }
+
+    #[test]
+    fn environment_overrides_file() {
+        let mut config = ServerConfig::from_toml("[oauth]\nclient_id = \"abc\"").unwrap();
+        let problems = config.apply_overrides(vars(&[
+            ("OAUTH_CLIENT_ID", "xyz"),
+            ("DATABASE_URL", "postgres://localhost/cantina"),
+            ("RATE_LIMIT_PING", "1/1"),
+            ("BIND_ADDRESS", "not an address"),
+        ]));
+
+        assert_eq!(config.oauth.client_id, "xyz");
+        config.normalize();
+        assert_eq!(
+            config.rate_limits.get("ping"),
+            Some(RateLimit::new(1, Duration::from_secs(1)))
+        );
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("BIND_ADDRESS"));
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let config = ServerConfig {
+            public_url: Url::parse("mailto:admin@example.com").unwrap(),
+            oauth: OAuthConfig {
+                flow: OAuthFlow::Code,
+                ..OAuthConfig::default()
+            },
+            logging: LoggingConfig {
+                level: "server=loud".to_owned(),
+                ..LoggingConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+        let problems = config.validate();
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+    }
+}
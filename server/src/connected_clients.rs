@@ -0,0 +1,511 @@
+use crate::config::CONFIG;
+use crate::outbox::Outbox;
+use dashmap::{mapref::entry::Entry, DashMap};
+use lazy_static::lazy_static;
+use shared::{ResponseEnvelope, ServerResponse, UserProfile};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+lazy_static! {
+    pub static ref CONNECTED_CLIENTS: ConnectedClients = ConnectedClients::default();
+}
+
+/// The sessions connected to this server, keyed by installation. Everything
+/// about a session, including the account it is logged into, lives in its
+/// entry, so every change is made under that entry's lock alone. Account-wide
+/// messages scan the sessions rather than keep an index that could disagree.
+pub struct ConnectedClients {
+    sessions: DashMap<Uuid, Session>,
+    events: broadcast::Sender<SessionEvent>,
+}
+
+impl Default for ConnectedClients {
+    fn default() -> Self {
+        let (events, _) = broadcast::channel(SESSION_EVENT_CAPACITY);
+        Self {
+            sessions: DashMap::default(),
+            events,
+        }
+    }
+}
+
+const SESSION_EVENT_CAPACITY: usize = 1024;
+
+/// Changes to sessions that other subsystems may want to react to. Subscribe
+/// with `ConnectedClients::subscribe`.
+#[derive(Clone, Debug)]
+pub enum SessionEvent {
+    /// The installation's websocket closed. It may still resume its session
+    /// within the configured session grace period.
+    Disconnected {
+        installation_id: Uuid,
+        account_id: Option<i64>,
+        reason: DisconnectReason,
+    },
+    /// The installation didn't resume in time and its session was removed.
+    Expired {
+        installation_id: Uuid,
+        account_id: Option<i64>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisconnectReason {
+    Closed,
+    Error,
+    Unresponsive,
+    /// The client fell too far behind reading its messages.
+    Overflowed,
+}
+
+/// The state kept for an installation while it is connected, and for
+/// the configured grace period after it disconnects. Messages sent while it is
+/// disconnected are queued in `undelivered` and delivered if the client resumes.
+/// Sessions are local to this process, so resuming only works if the client
+/// reconnects to the same server.
+struct Session {
+    resume_token: Uuid,
+    profile: Option<UserProfile>,
+    outbox: Option<Arc<Outbox>>,
+    undelivered: VecDeque<ResponseEnvelope>,
+    disconnected_at: Option<Instant>,
+}
+
+impl Session {
+    fn send(&mut self, envelope: ResponseEnvelope) {
+        match &self.outbox {
+            Some(outbox) => outbox.push(envelope),
+            None => {
+                if self.undelivered.len() >= MAX_UNDELIVERED_LENGTH {
+                    self.undelivered.pop_front();
+                }
+                self.undelivered.push_back(envelope);
+            }
+        }
+    }
+
+    fn is_expired(&self, grace_period: Duration) -> bool {
+        self.disconnected_at
+            .map(|disconnected_at| disconnected_at.elapsed() >= grace_period)
+            .unwrap_or(false)
+    }
+
+    fn account_id(&self) -> Option<i64> {
+        self.profile.as_ref().map(|profile| profile.id)
+    }
+}
+
+const MAX_UNDELIVERED_LENGTH: usize = 256;
+
+/// A session, as seen by `ConnectedClients::broadcast_filtered`.
+pub struct ConnectedSession<'a> {
+    pub profile: Option<&'a UserProfile>,
+}
+
+pub struct ResumedSession {
+    pub profile: Option<UserProfile>,
+}
+
+impl ConnectedClients {
+    /// Starts a new session for `installation_id`, replacing any previous
+    /// one. Returns the token the client can use to resume it.
+    pub fn connect(&self, installation_id: Uuid, outbox: Arc<Outbox>) -> Uuid {
+        let resume_token = Uuid::new_v4();
+        let session = Session {
+            resume_token,
+            profile: None,
+            outbox: Some(outbox),
+            undelivered: VecDeque::new(),
+            disconnected_at: None,
+        };
+        self.sessions.insert(installation_id, session);
+        resume_token
+    }
+
+    /// Reattaches a client to its existing session if `resume_token` matches,
+    /// delivering any messages queued while it was disconnected. Returns `None`
+    /// for sessions this server doesn't have, including ones that live on
+    /// another replica, in which case the client gets a fresh session.
+    pub fn resume(
+        &self,
+        installation_id: Uuid,
+        resume_token: Uuid,
+        outbox: Arc<Outbox>,
+    ) -> Option<ResumedSession> {
+        let mut session = self.sessions.get_mut(&installation_id)?;
+        if session.resume_token != resume_token {
+            return None;
+        }
+
+        for envelope in session.undelivered.drain(..) {
+            outbox.push(envelope);
+        }
+        session.outbox = Some(outbox);
+        session.disconnected_at = None;
+        Some(ResumedSession {
+            profile: session.profile.clone(),
+        })
+    }
+
+    pub fn associate_account(&self, installation_id: Uuid, profile: UserProfile) {
+        if let Some(mut session) = self.sessions.get_mut(&installation_id) {
+            session.profile = Some(profile);
+        }
+    }
+
+    /// Marks the session as disconnected if `outbox` is still the one it is
+    /// attached to, returning the session's resume token. The session is kept
+    /// until `expire_sessions` removes it.
+    pub fn disconnect(
+        &self,
+        installation_id: Uuid,
+        outbox: &Arc<Outbox>,
+        reason: DisconnectReason,
+    ) -> Option<Uuid> {
+        let (resume_token, account_id) = match self.sessions.get_mut(&installation_id) {
+            Some(mut session) => {
+                let is_current = session
+                    .outbox
+                    .as_ref()
+                    .map(|current| Arc::ptr_eq(current, outbox))
+                    .unwrap_or(false);
+                if !is_current {
+                    return None;
+                }
+                session.outbox = None;
+                session.disconnected_at = Some(Instant::now());
+                (session.resume_token, session.account_id())
+            }
+            None => return None,
+        };
+
+        self.emit(SessionEvent::Disconnected {
+            installation_id,
+            account_id,
+            reason,
+        });
+        Some(resume_token)
+    }
+
+    /// Gives a disconnected session back the messages its outbox never wrote,
+    /// ahead of anything queued since, so they're delivered if the client
+    /// resumes. They're dropped if the session was replaced or has expired.
+    pub fn return_unsent(
+        &self,
+        installation_id: Uuid,
+        resume_token: Uuid,
+        unsent: Vec<ResponseEnvelope>,
+    ) {
+        let mut session = match self.sessions.get_mut(&installation_id) {
+            Some(session) if session.resume_token == resume_token => session,
+            _ => return,
+        };
+        // Heartbeats only mean something on the connection they were sent on.
+        let unsent = unsent.into_iter().filter(|envelope| {
+            !matches!(
+                envelope.response,
+                ServerResponse::Ping | ServerResponse::Pong
+            )
+        });
+        match session.outbox.clone() {
+            // The client has already resumed on a new connection.
+            Some(outbox) => unsent.for_each(|envelope| outbox.push(envelope)),
+            None => {
+                let mut undelivered = unsent.collect::<VecDeque<_>>();
+                undelivered.append(&mut session.undelivered);
+                while undelivered.len() > MAX_UNDELIVERED_LENGTH {
+                    undelivered.pop_front();
+                }
+                session.undelivered = undelivered;
+            }
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: SessionEvent) {
+        // Sending only fails when nothing is subscribed.
+        self.events.send(event).unwrap_or_default();
+    }
+
+    /// Removes sessions that have been disconnected for longer than `grace_period`.
+    pub fn expire_sessions(&self, grace_period: Duration) {
+        let expired = self
+            .sessions
+            .iter()
+            .filter(|session| session.is_expired(grace_period))
+            .map(|session| *session.key())
+            .collect::<Vec<_>>();
+
+        for installation_id in expired {
+            // The session may have been resumed since it was collected.
+            if let Entry::Occupied(entry) = self.sessions.entry(installation_id) {
+                if entry.get().is_expired(grace_period) {
+                    let account_id = entry.get().account_id();
+                    entry.remove();
+                    self.emit(SessionEvent::Expired {
+                        installation_id,
+                        account_id,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Sends `message` to every installation on this server that is logged
+    /// into `account_id`. `pubsub` calls this for `MessageTarget::Account`
+    /// messages, which reach every replica.
+    pub fn send_to_account(&self, account_id: i64, message: ServerResponse) {
+        self.broadcast_filtered(message, |session| {
+            session.profile.map(|profile| profile.id) == Some(account_id)
+        })
+    }
+
+    /// Sends `message` to every installation with a session.
+    pub fn broadcast(&self, message: ServerResponse) {
+        self.broadcast_filtered(message, |_| true)
+    }
+
+    /// Sends `message` to every installation with a session that `predicate`
+    /// returns true for. Disconnected installations get it if they resume.
+    pub fn broadcast_filtered<F: Fn(&ConnectedSession<'_>) -> bool>(
+        &self,
+        message: ServerResponse,
+        predicate: F,
+    ) {
+        for mut session in self.sessions.iter_mut() {
+            let connected = ConnectedSession {
+                profile: session.profile.as_ref(),
+            };
+            if predicate(&connected) {
+                session.send(ResponseEnvelope {
+                    request_id: None,
+                    response: message.clone(),
+                });
+            }
+        }
+    }
+
+    /// The number of sessions, connected or waiting to be resumed.
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// The number of distinct accounts logged in on this server.
+    pub fn account_count(&self) -> usize {
+        self.sessions
+            .iter()
+            .filter_map(|session| session.account_id())
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    pub fn send_to_installation_id(&self, installation_id: Uuid, message: ServerResponse) {
+        if let Some(mut session) = self.sessions.get_mut(&installation_id) {
+            session.send(ResponseEnvelope {
+                request_id: None,
+                response: message,
+            });
+        }
+    }
+}
+
+pub async fn expire_sessions_loop() {
+    let grace_period = CONFIG.session_grace_period();
+    let mut interval = tokio::time::interval(grace_period / 2);
+    loop {
+        interval.tick().await;
+        CONNECTED_CLIENTS.expire_sessions(grace_period);
+    }
+}
+
+pub async fn log_session_events() {
+    let mut events = CONNECTED_CLIENTS.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(SessionEvent::Disconnected {
+                installation_id,
+                account_id,
+                reason,
+            }) => info!(%installation_id, ?account_id, ?reason, "Disconnected"),
+            Ok(SessionEvent::Expired {
+                installation_id,
+                account_id,
+            }) => info!(%installation_id, ?account_id, "Session expired"),
+            Err(broadcast::RecvError::Lagged(skipped)) => {
+                warn!(skipped, "Skipped session events")
+            }
+            Err(broadcast::RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbox::OutboxSettings;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn no_leaks_after_concurrent_connects_and_disconnects() {
+        let clients = Arc::new(ConnectedClients::default());
+        let threads = (0..8)
+            .map(|thread_index| {
+                let clients = clients.clone();
+                thread::spawn(move || {
+                    for iteration in 0..1_000 {
+                        // A small pool of installations and accounts, so
+                        // threads contend over the same entries.
+                        let installation_id = Uuid::from_u128(iteration % 16);
+                        let account_id = (thread_index + iteration as i64) % 4;
+                        let outbox = Outbox::new(OutboxSettings::default());
+
+                        let resume_token = clients.connect(installation_id, outbox.clone());
+                        clients.associate_account(
+                            installation_id,
+                            UserProfile {
+                                id: account_id,
+                                username: format!("user{}", account_id),
+                            },
+                        );
+                        clients.send_to_account(account_id, ServerResponse::Pong);
+                        clients.broadcast_filtered(ServerResponse::Ping, |session| {
+                            session.profile.is_some()
+                        });
+                        clients.disconnect(installation_id, &outbox, DisconnectReason::Closed);
+                        if iteration % 3 == 0 {
+                            clients.resume(installation_id, resume_token, outbox.clone());
+                            clients.disconnect(installation_id, &outbox, DisconnectReason::Closed);
+                        }
+                        clients.expire_sessions(Duration::from_secs(60));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Every session is disconnected, so a zero grace period expires them all.
+        clients.expire_sessions(Duration::from_secs(0));
+        assert!(clients.sessions.is_empty());
+    }
+
+    #[test]
+    fn account_messages_follow_logins() {
+        let clients = ConnectedClients::default();
+        let installation_id = Uuid::new_v4();
+        let outbox = Outbox::new(OutboxSettings::default());
+        let profile = |id| UserProfile {
+            id,
+            username: "ecton".to_owned(),
+        };
+
+        clients.connect(installation_id, outbox.clone());
+        clients.associate_account(installation_id, profile(1));
+        clients.associate_account(installation_id, profile(2));
+        clients.send_to_account(1, ServerResponse::Pong);
+        assert_eq!(outbox.len(), 0);
+        clients.send_to_account(2, ServerResponse::Pong);
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(clients.account_count(), 1);
+
+        // Reconnecting starts a new, logged out session.
+        clients.connect(installation_id, outbox);
+        assert_eq!(clients.account_count(), 0);
+    }
+
+    #[test]
+    fn unsent_messages_are_delivered_on_resume() {
+        let clients = ConnectedClients::default();
+        let installation_id = Uuid::new_v4();
+        let outbox = Outbox::new(OutboxSettings::default());
+        let unsolicited = |response| ResponseEnvelope {
+            request_id: None,
+            response,
+        };
+
+        clients.connect(installation_id, outbox.clone());
+        let resume_token = clients
+            .disconnect(installation_id, &outbox, DisconnectReason::Error)
+            .unwrap();
+        clients.send_to_installation_id(
+            installation_id,
+            ServerResponse::RateLimited {
+                retry_after: Duration::from_secs(1),
+            },
+        );
+        clients.return_unsent(
+            installation_id,
+            resume_token,
+            vec![
+                unsolicited(ServerResponse::Ping),
+                unsolicited(ServerResponse::AuthenticateAtUrl {
+                    url: "https://itch.io".to_owned(),
+                }),
+            ],
+        );
+
+        let resumed = Outbox::new(OutboxSettings::default());
+        clients.resume(installation_id, resume_token, resumed.clone());
+        resumed.close();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut received = Vec::new();
+        while let Ok(envelope) = runtime.block_on(resumed.recv()) {
+            received.push(envelope.response);
+        }
+        assert!(matches!(
+            received[..],
+            [
+                ServerResponse::AuthenticateAtUrl { .. },
+                ServerResponse::RateLimited { .. }
+            ]
+        ));
+
+        // A new session doesn't get the old one's messages.
+        let replaced = Outbox::new(OutboxSettings::default());
+        clients.connect(installation_id, replaced.clone());
+        clients.return_unsent(
+            installation_id,
+            resume_token,
+            vec![unsolicited(ServerResponse::Pong)],
+        );
+        assert_eq!(replaced.len(), 0);
+    }
+
+    #[test]
+    fn disconnect_events_only_for_current_sender() {
+        let clients = ConnectedClients::default();
+        let mut events = clients.subscribe();
+        let installation_id = Uuid::new_v4();
+        let replaced = Outbox::new(OutboxSettings::default());
+        let current = Outbox::new(OutboxSettings::default());
+
+        clients.connect(installation_id, replaced.clone());
+        clients.connect(installation_id, current.clone());
+        clients.disconnect(installation_id, &replaced, DisconnectReason::Closed);
+        clients.disconnect(installation_id, &current, DisconnectReason::Unresponsive);
+        clients.expire_sessions(Duration::from_secs(0));
+
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            SessionEvent::Disconnected {
+                reason: DisconnectReason::Unresponsive,
+                ..
+            }
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            SessionEvent::Expired { .. }
+        ));
+        assert!(events.try_recv().is_err());
+    }
+}
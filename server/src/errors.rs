@@ -0,0 +1,88 @@
+use crate::identity::ProviderError;
+use serde_derive::Serialize;
+use shared::{ErrorCode, LoginFailure};
+use std::fmt;
+use warp::http::StatusCode;
+
+/// An error that should be reported to the client with a specific code.
+/// Any other error returned while handling a request is reported as
+/// `ErrorCode::Internal`, with the details only logged on the server.
+#[derive(Debug)]
+pub struct RequestError(pub ErrorCode);
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+pub fn error_code(err: &anyhow::Error) -> ErrorCode {
+    match err.downcast_ref::<RequestError>() {
+        Some(RequestError(code)) => *code,
+        None => ErrorCode::Internal,
+    }
+}
+
+/// Why a login didn't work, as far as the player needs to know.
+pub fn login_failure(err: &anyhow::Error) -> LoginFailure {
+    match err.downcast_ref::<ProviderError>() {
+        Some(ProviderError::Rejected) => LoginFailure::Rejected,
+        Some(ProviderError::Unavailable(_)) => LoginFailure::ProviderUnavailable,
+        None => LoginFailure::Internal,
+    }
+}
+
+/// Why a login page request was turned down. The JSON body of the response,
+/// which `logged_in.html` shows the player.
+#[derive(Debug, Serialize)]
+pub struct LoginPageError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    pub error: &'static str,
+    pub message: &'static str,
+}
+
+impl LoginPageError {
+    pub fn wrong_flow() -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            error: "wrong_flow",
+            message: "This server doesn't accept logins this way.",
+        }
+    }
+
+    pub fn missing_field() -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            error: "missing_field",
+            message: "Some login details were missing. Please log in again from the game.",
+        }
+    }
+
+    pub fn invalid_state() -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            error: "invalid_state",
+            message: "This login link expired or was already used. Please log in again.",
+        }
+    }
+
+    pub fn login_failed(failure: LoginFailure) -> Self {
+        let (status, error) = match failure {
+            LoginFailure::Rejected => (StatusCode::BAD_REQUEST, "rejected"),
+            LoginFailure::ProviderUnavailable => (StatusCode::BAD_GATEWAY, "provider_unavailable"),
+            LoginFailure::Internal => (StatusCode::INTERNAL_SERVER_ERROR, "internal"),
+        };
+        Self {
+            status,
+            error,
+            message: failure.message(),
+        }
+    }
+
+    pub fn into_reply(self) -> impl warp::Reply {
+        warp::reply::with_status(warp::reply::json(&self), self.status)
+    }
+}
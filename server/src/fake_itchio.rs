@@ -0,0 +1,71 @@
+//! A stand-in for itch.io's OAuth and API endpoints, for tests.
+
+use serde_json::json;
+use std::collections::HashMap;
+use url::Url;
+use warp::{http::StatusCode, Filter};
+
+pub const CLIENT_SECRET: &str = "secret";
+pub const REDIRECT_URI: &str = "https://cantina.example.com/auth/itchio_callback";
+pub const CODE: &str = "code-from-redirect";
+pub const ACCESS_TOKEN: &str = "access-token";
+
+/// Starts a server that knows a single player, who logs in with `CODE`
+/// through the code flow or with `ACCESS_TOKEN`. Codes are only exchanged for
+/// clients that know `CLIENT_SECRET`. Returns the server's base URL.
+pub fn start(player_id: i64, username: &str) -> Url {
+    let token = warp::path!("oauth" / "token")
+        .and(warp::post())
+        .and(warp::body::form())
+        .map(|form: HashMap<String, String>| {
+            let field = |name: &str| form.get(name).map(String::as_str);
+            if field("grant_type") == Some("authorization_code")
+                && field("code") == Some(CODE)
+                && field("redirect_uri") == Some(REDIRECT_URI)
+                && field("client_secret") == Some(CLIENT_SECRET)
+            {
+                warp::reply::with_status(
+                    warp::reply::json(&json!({
+                        "access_token": ACCESS_TOKEN,
+                        "token_type": "bearer",
+                    })),
+                    StatusCode::OK,
+                )
+            } else {
+                warp::reply::with_status(
+                    warp::reply::json(&json!({ "error": "invalid_grant" })),
+                    StatusCode::BAD_REQUEST,
+                )
+            }
+        });
+
+    let user = json!({
+        "cover_url": null,
+        "display_name": null,
+        "username": username,
+        "id": player_id,
+        "developer": false,
+        "gamer": true,
+        "press_user": false,
+        "url": format!("https://{}.itch.io", username),
+    });
+    let profile = warp::path!("api" / "1" / "key" / "me")
+        .and(warp::header::<String>("authorization"))
+        .map(move |authorization: String| {
+            if authorization == format!("Bearer {}", ACCESS_TOKEN) {
+                warp::reply::with_status(
+                    warp::reply::json(&json!({ "user": user })),
+                    StatusCode::OK,
+                )
+            } else {
+                warp::reply::with_status(
+                    warp::reply::json(&json!({ "errors": ["invalid key"] })),
+                    StatusCode::UNAUTHORIZED,
+                )
+            }
+        });
+
+    let (address, server) = warp::serve(token.or(profile)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    Url::parse(&format!("http://{}/", address)).unwrap()
+}
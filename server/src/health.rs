@@ -0,0 +1,193 @@
+use crate::metrics;
+use crate::shutdown::is_shutting_down;
+use migrations::{pg, sqlx};
+use serde_derive::Serialize;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use warp::http::StatusCode;
+
+static LISTENER_ALIVE: AtomicBool = AtomicBool::new(false);
+
+const POSTGRES_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Called by `pubsub::pg_notify_loop` once it is listening, and again when it
+/// stops. Without the listener, logins and messages from other replicas
+/// never reach this server's clients.
+pub fn set_listener_alive(alive: bool) {
+    LISTENER_ALIVE.store(alive, Ordering::SeqCst);
+}
+
+pub fn listener_alive() -> bool {
+    LISTENER_ALIVE.load(Ordering::SeqCst)
+}
+
+#[derive(Serialize)]
+struct Liveness {
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    status: &'static str,
+    shutting_down: bool,
+    checks: Checks,
+}
+
+#[derive(Serialize)]
+struct Checks {
+    postgres: Check,
+    migrations: Check,
+    pubsub_listener: Check,
+}
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn from_flag(flag: bool, error: &str) -> Self {
+        if flag {
+            Self::ok()
+        } else {
+            Self::failed(error)
+        }
+    }
+
+    fn ok() -> Self {
+        Self {
+            ok: true,
+            error: None,
+        }
+    }
+
+    fn failed<S: ToString>(error: S) -> Self {
+        Self {
+            ok: false,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// `/healthz`: the process is up and serving requests.
+pub fn liveness() -> impl warp::Reply {
+    warp::reply::json(&Liveness { status: "ok" })
+}
+
+/// `/readyz`: this replica can take new websocket connections.
+pub async fn readiness() -> Result<impl warp::Reply, std::convert::Infallible> {
+    let checks = Checks {
+        postgres: check_postgres().await,
+        migrations: check_migrations().await,
+        pubsub_listener: Check::from_flag(listener_alive(), "Not listening for notifications"),
+    };
+    let shutting_down = is_shutting_down();
+    let ready =
+        !shutting_down && checks.postgres.ok && checks.migrations.ok && checks.pubsub_listener.ok;
+
+    let readiness = Readiness {
+        status: if ready { "ready" } else { "not_ready" },
+        shutting_down,
+        checks,
+    };
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&readiness),
+        status,
+    ))
+}
+
+async fn check_postgres() -> Check {
+    let pool = pg();
+    let query = metrics::time_query("health_check", sqlx::query("SELECT 1").execute(&pool));
+    match tokio::time::timeout(POSTGRES_TIMEOUT, query).await {
+        Ok(Ok(_)) => Check::ok(),
+        Ok(Err(err)) => Check::failed(err),
+        Err(_) => Check::failed("Timed out"),
+    }
+}
+
+/// Checks that the database has exactly the migrations this build has. A
+/// newer build sharing the database may have applied ones it doesn't know.
+async fn check_migrations() -> Check {
+    let pool = pg();
+    let query = metrics::time_query(
+        "applied_migrations",
+        sqlx::query_as::<_, (String,)>("SELECT name FROM migrations").fetch_all(&pool),
+    );
+    match tokio::time::timeout(POSTGRES_TIMEOUT, query).await {
+        Ok(Ok(applied)) => {
+            let expected = migrations::migrations()
+                .into_iter()
+                .map(|migration| migration.name)
+                .collect::<Vec<_>>();
+            let applied = applied.into_iter().map(|(name,)| name).collect::<Vec<_>>();
+            compare_migrations(&expected, &applied)
+        }
+        Ok(Err(err)) => Check::failed(err),
+        Err(_) => Check::failed("Timed out"),
+    }
+}
+
+fn compare_migrations(expected: &[String], applied: &[String]) -> Check {
+    let missing = expected
+        .iter()
+        .filter(|name| !applied.contains(name))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let unknown = applied
+        .iter()
+        .filter(|name| !expected.contains(name))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+
+    let mut problems = Vec::new();
+    if !missing.is_empty() {
+        problems.push(format!("Missing {}", missing.join(", ")));
+    }
+    if !unknown.is_empty() {
+        problems.push(format!("Unknown {}", unknown.join(", ")));
+    }
+    if problems.is_empty() {
+        Check::ok()
+    } else {
+        Check::failed(problems.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_must_match_exactly() {
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        let expected = names(&["0001", "0002"]);
+
+        assert!(compare_migrations(&expected, &names(&["0002", "0001"])).ok);
+
+        let behind = compare_migrations(&expected, &names(&["0001"]));
+        assert!(!behind.ok);
+        assert_eq!(behind.error.as_deref(), Some("Missing 0002"));
+
+        let ahead = compare_migrations(&expected, &names(&["0001", "0002", "0003"]));
+        assert!(!ahead.ok);
+        assert_eq!(ahead.error.as_deref(), Some("Unknown 0003"));
+
+        let both = compare_migrations(&expected, &names(&["0002", "0003"]));
+        assert_eq!(both.error.as_deref(), Some("Missing 0001; Unknown 0003"));
+    }
+}
@@ -0,0 +1,61 @@
+use crate::config::CONFIG;
+use crate::itchio::Itchio;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use std::{fmt, sync::Arc};
+
+lazy_static! {
+    pub static ref IDENTITY_PROVIDER: Arc<dyn IdentityProvider> = Arc::new(Itchio::new(
+        &CONFIG.oauth,
+        CONFIG.public_url_for("auth/itchio_callback")
+    ));
+}
+
+/// A player, as their identity provider knows them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    /// The provider's id for the player, which is only unique within the
+    /// provider.
+    pub external_id: String,
+    pub username: String,
+}
+
+/// Where players log in.
+#[async_trait]
+pub trait IdentityProvider: Send + Sync {
+    /// Identifies the provider in `account_identities`. Changing it unlinks
+    /// every account that logged in through the provider.
+    fn name(&self) -> &'static str;
+
+    /// Where to send a player to log in. The provider hands `state` back to
+    /// the server along with the token or code.
+    fn authorization_url(&self, state: &str) -> String;
+
+    /// Exchanges the code from a `code` flow redirect for an access token.
+    async fn exchange_code(&self, code: &str) -> Result<String, ProviderError>;
+
+    /// Who `access_token` belongs to.
+    async fn identify(&self, access_token: &str) -> Result<Identity, ProviderError>;
+}
+
+#[derive(Debug)]
+pub enum ProviderError {
+    /// The provider turned the code or token down.
+    Rejected,
+    /// The provider couldn't be reached, or answered with something
+    /// unexpected.
+    Unavailable(anyhow::Error),
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderError::Rejected => f.write_str("Rejected by the identity provider"),
+            ProviderError::Unavailable(err) => {
+                write!(f, "Identity provider unavailable: {:#}", err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
@@ -0,0 +1,215 @@
+use crate::config::{OAuthConfig, OAuthFlow};
+use crate::identity::{Identity, IdentityProvider, ProviderError};
+use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
+use tracing::info_span;
+use tracing_futures::Instrument;
+use url::Url;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ItchioProfile {
+    pub cover_url: Option<String>,
+    pub display_name: Option<String>,
+    pub username: String,
+    pub id: i64,
+    pub developer: bool,
+    pub gamer: bool,
+    pub press_user: bool,
+    pub url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ItchioProfileResponse {
+    pub user: ItchioProfile,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+/// itch.io, reached at `oauth.itchio_url`.
+pub struct Itchio {
+    http: reqwest::Client,
+    oauth: OAuthConfig,
+    redirect_uri: Url,
+}
+
+impl Itchio {
+    pub fn new(oauth: &OAuthConfig, redirect_uri: Url) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            oauth: oauth.clone(),
+            redirect_uri,
+        }
+    }
+
+    fn endpoint(&self, path: &str) -> Url {
+        self.oauth
+            .itchio_url
+            .join(path)
+            .expect("Endpoint paths are relative")
+    }
+}
+
+#[async_trait]
+impl IdentityProvider for Itchio {
+    fn name(&self) -> &'static str {
+        "itchio"
+    }
+
+    fn authorization_url(&self, state: &str) -> String {
+        let response_type = match self.oauth.flow {
+            OAuthFlow::Implicit => "token",
+            OAuthFlow::Code => "code",
+        };
+        let mut url = self.endpoint("user/oauth");
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.oauth.client_id)
+            .append_pair("scope", "profile:me")
+            .append_pair("response_type", response_type)
+            .append_pair("redirect_uri", self.redirect_uri.as_str())
+            .append_pair("state", state);
+        url.to_string()
+    }
+
+    /// `oauth/token` is the conventional OAuth endpoint, but itch.io doesn't
+    /// document one; only the fake itch.io in the tests is known to answer it.
+    async fn exchange_code(&self, code: &str) -> Result<String, ProviderError> {
+        let response = async {
+            self.http
+                .post(self.endpoint("oauth/token"))
+                .form(&[
+                    ("grant_type", "authorization_code"),
+                    ("code", code),
+                    ("redirect_uri", self.redirect_uri.as_str()),
+                    ("client_id", self.oauth.client_id.as_str()),
+                    ("client_secret", self.oauth.client_secret.as_str()),
+                ])
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<TokenResponse>()
+                .await
+        }
+        .instrument(info_span!("itchio_token"))
+        .await
+        .map_err(provider_error)?;
+        Ok(response.access_token)
+    }
+
+    async fn identify(&self, access_token: &str) -> Result<Identity, ProviderError> {
+        let response = async {
+            self.http
+                .get(self.endpoint("api/1/key/me"))
+                .bearer_auth(access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<ItchioProfileResponse>()
+                .await
+        }
+        .instrument(info_span!("itchio_profile"))
+        .await
+        .map_err(provider_error)?;
+        Ok(Identity {
+            external_id: response.user.id.to_string(),
+            username: response.user.username,
+        })
+    }
+}
+
+/// itch.io answers tokens and codes it won't accept with a client error.
+fn provider_error(err: reqwest::Error) -> ProviderError {
+    match err.status() {
+        Some(status) if status.is_client_error() => ProviderError::Rejected,
+        _ => ProviderError::Unavailable(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_itchio::{self, ACCESS_TOKEN, CLIENT_SECRET, CODE, REDIRECT_URI};
+    use std::collections::HashMap;
+
+    fn itchio(flow: OAuthFlow, itchio_url: Url) -> Itchio {
+        let oauth = OAuthConfig {
+            client_id: "cantina".to_owned(),
+            client_secret: CLIENT_SECRET.to_owned(),
+            flow,
+            itchio_url,
+            ..OAuthConfig::default()
+        };
+        Itchio::new(&oauth, Url::parse(REDIRECT_URI).unwrap())
+    }
+
+    fn query(url: &str) -> HashMap<String, String> {
+        Url::parse(url)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect()
+    }
+
+    #[test]
+    fn authorization_url_matches_flow() {
+        let itchio_url = Url::parse("https://itch.io/").unwrap();
+        let implicit =
+            query(&itchio(OAuthFlow::Implicit, itchio_url.clone()).authorization_url("abc"));
+        assert_eq!(implicit["response_type"], "token");
+        let code = query(&itchio(OAuthFlow::Code, itchio_url).authorization_url("abc"));
+        assert_eq!(code["response_type"], "code");
+        assert_eq!(code["state"], "abc");
+        assert_eq!(code["redirect_uri"], REDIRECT_URI);
+    }
+
+    #[tokio::test]
+    async fn code_flow() {
+        let itchio = itchio(OAuthFlow::Code, fake_itchio::start(42, "player"));
+        let access_token = itchio.exchange_code(CODE).await.unwrap();
+        assert_eq!(access_token, ACCESS_TOKEN);
+        assert_eq!(
+            itchio.identify(&access_token).await.unwrap(),
+            Identity {
+                external_id: "42".to_owned(),
+                username: "player".to_owned()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_by_itchio() {
+        let itchio = itchio(OAuthFlow::Code, fake_itchio::start(42, "player"));
+        assert!(matches!(
+            itchio.exchange_code("forged").await,
+            Err(ProviderError::Rejected)
+        ));
+        assert!(matches!(
+            itchio.identify("forged").await,
+            Err(ProviderError::Rejected)
+        ));
+
+        let wrong_secret = Itchio::new(
+            &OAuthConfig {
+                client_secret: "guess".to_owned(),
+                ..itchio.oauth.clone()
+            },
+            itchio.redirect_uri.clone(),
+        );
+        assert!(matches!(
+            wrong_secret.exchange_code(CODE).await,
+            Err(ProviderError::Rejected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unreachable_itchio() {
+        // Nothing listens on port 1, so connections are refused.
+        let itchio = itchio(OAuthFlow::Code, Url::parse("http://127.0.0.1:1/").unwrap());
+        assert!(matches!(
+            itchio.identify(ACCESS_TOKEN).await,
+            Err(ProviderError::Unavailable(_))
+        ));
+    }
+}
@@ -0,0 +1,13 @@
+use crate::config::{LogFormat, LoggingConfig};
+use tracing_subscriber::EnvFilter;
+
+/// Installs the global subscriber. `config` has already been validated, so
+/// its filter is known to parse.
+pub fn init(config: &LoggingConfig) {
+    let filter = EnvFilter::try_new(&config.level).expect("Invalid log level");
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_span_list(true).init(),
+    }
+}
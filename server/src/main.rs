@@ -1,135 +1,518 @@
+use config::{OAuthFlow, CONFIG};
+use errors::{login_failure, LoginPageError};
+use identity::{IdentityProvider, IDENTITY_PROVIDER};
 use lazy_static::lazy_static;
 use migrations::{pg, sqlx};
-use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
+use shared::{LoginFailure, ServerResponse, UserProfile};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 use tera::Tera;
+use tracing::{error, info_span, warn};
+use tracing_futures::Instrument;
+use url::Url;
 use uuid::Uuid;
 use warp::http::{header, StatusCode};
 use warp::Filter;
 
+mod config;
+mod connected_clients;
+mod errors;
+#[cfg(test)]
+mod fake_itchio;
+mod health;
+mod identity;
+mod itchio;
+mod logging;
+mod metrics;
+mod oauth_state;
+mod outbox;
+mod protocol;
+mod proxies;
 mod pubsub;
+mod rate_limit;
+mod shutdown;
 mod websockets;
 
+const LOGGED_IN_MESSAGE: &str = "Thank you for logging in. You can return to the game now.";
+
 lazy_static! {
     static ref TEMPLATES: Tera = {
         let mut tera = Tera::default();
         tera.add_raw_template("logged_in", include_str!("logged_in.html"))
             .unwrap();
+        tera.add_raw_template("login_complete", include_str!("login_complete.html"))
+            .unwrap();
         tera
     };
 }
 
 #[tokio::main]
 async fn main() {
-    dotenv::dotenv().expect("Error initializing environment");
+    // Settings can come from a config file instead, so `.env` is optional.
+    dotenv::dotenv().ok();
+    lazy_static::initialize(&CONFIG);
+    logging::init(&CONFIG.logging);
+    if CONFIG.oauth.flow == OAuthFlow::Code {
+        warn!("The code flow relies on an itch.io token endpoint that isn't documented");
+    }
+    migrations::configure_pool(&CONFIG.database.url, CONFIG.database.pool_size);
 
     migrations::run_all()
         .await
         .expect("Error running migrations");
 
     tokio::spawn(pubsub::pg_notify_loop());
+    tokio::spawn(connected_clients::expire_sessions_loop());
+    tokio::spawn(connected_clients::log_session_events());
+    tokio::spawn(outbox::log_stats_loop());
+    tokio::spawn(rate_limit::prune_loop());
 
     let websockets = warp::path!("ws")
         .and(warp::path::end())
         .and(warp::ws())
-        .map(|ws: warp::ws::Ws| ws.on_upgrade(|websocket| websockets::main(websocket)));
+        .and(warp::query())
+        .and(remote_address())
+        .map(
+            |ws: warp::ws::Ws,
+             options: websockets::WebsocketOptions,
+             remote_address: Option<IpAddr>| {
+                if shutdown::is_shutting_down() {
+                    Box::new(warp::reply::with_status(
+                        "Shutting down",
+                        StatusCode::SERVICE_UNAVAILABLE,
+                    )) as Box<dyn warp::Reply>
+                } else {
+                    Box::new(ws.on_upgrade(move |websocket| {
+                        websockets::main(websocket, options, remote_address)
+                    }))
+                }
+            },
+        );
     // let client_authorize = warp::path!("auth" / "client").map(|| oauth_client_authenticate());
-    let itchio_callback = warp::path!("auth" / "itchio_callback").map(|| itchio_callback());
-    let receive_token = warp::path!("auth" / "receive_token")
-        .and(warp::body::form())
-        .map(|body: HashMap<String, String>| {
-            receive_token(&body["state"], body["access_token"].clone())
-        });
-    let oauth = itchio_callback.or(receive_token);
+    let oauth = login_routes(LoginSettings {
+        provider: IDENTITY_PROVIDER.clone(),
+        flow: CONFIG.oauth.flow,
+        receive_token_url: CONFIG.public_url_for("auth/receive_token"),
+    });
+    let healthz = warp::path!("healthz").map(health::liveness);
+    let readyz = warp::path!("readyz").and_then(health::readiness);
     let routes = websockets
         .or(oauth)
+        .or(healthz)
+        .or(readyz)
         .or(warp::any().map(|| warp::reply::with_status("Not Found", StatusCode::NOT_FOUND)));
 
-    warp::serve(routes).run(([0, 0, 0, 0], 7878)).await;
+    tokio::spawn(
+        warp::serve(warp::path!("metrics").map(metrics::render)).run(CONFIG.metrics_bind_address),
+    );
+    let (_, server) = warp::serve(routes)
+        .bind_with_graceful_shutdown(CONFIG.bind_address, shutdown::signal_received());
+    server.await;
+
+    // Websockets outlive the HTTP server, so give their queued messages a
+    // chance to be delivered before exiting.
+    shutdown::drain_outboxes().await;
 }
 
-fn itchio_callback() -> impl warp::reply::Reply {
-    let mut context = tera::Context::new();
-    context.insert("post_url", "http://localhost:7878/auth/receive_token");
+/// The address of the connecting client. Behind the load balancer, the peer
+/// address is the load balancer itself, so `X-Forwarded-For` is used when the
+/// peer is one of `CONFIG.trusted_proxies`.
+fn remote_address() -> impl Filter<Extract = (Option<IpAddr>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("x-forwarded-for")
+        .and(warp::addr::remote())
+        .map(
+            |forwarded_for: Option<String>, remote: Option<SocketAddr>| {
+                remote.map(|remote| {
+                    CONFIG
+                        .trusted_proxies
+                        .client_address(remote.ip(), forwarded_for.as_deref())
+                })
+            },
+        )
+}
 
-    warp::reply::with_header(
-        TEMPLATES.render("logged_in", &context).unwrap(),
-        header::CONTENT_TYPE,
-        "text/html; charset=UTF-8",
-    )
+/// What the login routes need. Passed in rather than read from `CONFIG`, so
+/// tests can log in through a stand-in provider.
+#[derive(Clone)]
+struct LoginSettings {
+    provider: Arc<dyn IdentityProvider>,
+    flow: OAuthFlow,
+    receive_token_url: Url,
 }
 
-fn receive_token(state: &str, access_token: String) -> impl warp::reply::Reply {
-    let installation_id = match Uuid::parse_str(state) {
-        Ok(uuid) => uuid,
-        Err(_) => {
-            println!("Invalid UUID in state");
-            return StatusCode::INTERNAL_SERVER_ERROR;
-        }
+fn login_routes(
+    settings: LoginSettings,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let settings = warp::any().map(move || settings.clone());
+    let itchio_callback = warp::path!("auth" / "itchio_callback")
+        .and(settings.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(itchio_callback);
+    let receive_token = warp::path!("auth" / "receive_token")
+        .and(settings)
+        .and(warp::body::form::<HashMap<String, String>>())
+        .and_then(receive_token);
+    itchio_callback.or(receive_token)
+}
+
+async fn itchio_callback(
+    settings: LoginSettings,
+    params: HashMap<String, String>,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let mut context = tera::Context::new();
+    if settings.flow == OAuthFlow::Implicit {
+        context.insert("post_url", settings.receive_token_url.as_str());
+        return Ok(Box::new(html(
+            TEMPLATES.render("logged_in", &context).unwrap(),
+        )));
+    }
+
+    let (status, message) = match code_login(&*settings.provider, &params).await {
+        Ok(()) => (StatusCode::OK, LOGGED_IN_MESSAGE),
+        Err(err) => (err.status, err.message),
     };
-    tokio::spawn(async move {
-        login_itchio(installation_id, access_token)
-            .await
-            .expect("Error logging into itchio")
-    });
-    StatusCode::OK
+    context.insert("message", message);
+    Ok(Box::new(warp::reply::with_status(
+        html(TEMPLATES.render("login_complete", &context).unwrap()),
+        status,
+    )))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ItchioProfile {
-    pub cover_url: Option<String>,
-    pub display_name: Option<String>,
-    pub username: String,
-    pub id: i64,
-    pub developer: bool,
-    pub gamer: bool,
-    pub press_user: bool,
-    pub url: String,
+fn html(body: String) -> impl warp::Reply {
+    warp::reply::with_header(body, header::CONTENT_TYPE, "text/html; charset=UTF-8")
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ItchioProfileResponse {
-    pub user: ItchioProfile,
+/// `name` from a login page request, which must be present and not empty.
+fn required<'a>(
+    params: &'a HashMap<String, String>,
+    name: &str,
+) -> Result<&'a str, LoginPageError> {
+    match params.get(name) {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => Err(LoginPageError::missing_field()),
+    }
 }
 
-async fn login_itchio(installation_id: Uuid, access_token: String) -> Result<(), anyhow::Error> {
-    // Call itch.io API to get the user information
-    let client = reqwest::Client::new();
-    let response: ItchioProfileResponse = client
-        .get("https://itch.io/api/1/key/me")
-        .header(
-            reqwest::header::AUTHORIZATION,
-            format!("Bearer {}", access_token),
-        )
-        .send()
-        .await?
-        .json()
-        .await?;
+/// Finishes a `code` flow login. The access token is fetched by the server,
+/// so unlike `receive_token` it never passes through the browser.
+async fn code_login(
+    provider: &dyn IdentityProvider,
+    params: &HashMap<String, String>,
+) -> Result<(), LoginPageError> {
+    let state = required(params, "state")?;
+    let code = required(params, "code")?;
+    let installation_id = consume_state(state).await?;
+    let result = async {
+        let access_token = provider.exchange_code(code).await?;
+        login(provider, installation_id, access_token).await
+    }
+    .instrument(info_span!("itchio_login", %installation_id))
+    .await;
+    report_login(installation_id, result)
+        .await
+        .map_err(LoginPageError::login_failed)
+}
+
+async fn receive_token(
+    settings: LoginSettings,
+    form: HashMap<String, String>,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    if settings.flow != OAuthFlow::Implicit {
+        return Ok(Box::new(LoginPageError::wrong_flow().into_reply()));
+    }
+    Ok(match token_login(&*settings.provider, &form).await {
+        Ok(()) => Box::new(warp::reply::json(
+            &serde_json::json!({ "message": LOGGED_IN_MESSAGE }),
+        )),
+        Err(err) => Box::new(err.into_reply()),
+    })
+}
+
+/// Finishes an `implicit` flow login with the access token `logged_in.html`
+/// posted from the URL fragment.
+async fn token_login(
+    provider: &dyn IdentityProvider,
+    form: &HashMap<String, String>,
+) -> Result<(), LoginPageError> {
+    let state = required(form, "state")?;
+    let access_token = required(form, "access_token")?.to_owned();
+    let installation_id = consume_state(state).await?;
+    let result = login(provider, installation_id, access_token)
+        .instrument(info_span!("itchio_login", %installation_id))
+        .await;
+    report_login(installation_id, result)
+        .await
+        .map_err(LoginPageError::login_failed)
+}
+
+/// The installation `state` was issued to.
+async fn consume_state(state: &str) -> Result<Uuid, LoginPageError> {
+    match oauth_state::consume(state).await {
+        Ok(Some(installation_id)) => Ok(installation_id),
+        Ok(None) => {
+            warn!("Unknown, expired or reused OAuth state");
+            metrics::OAUTH_LOGINS
+                .with_label_values(&["invalid_state"])
+                .inc();
+            Err(LoginPageError::invalid_state())
+        }
+        Err(err) => {
+            error!(error = ?err, "Error checking OAuth state");
+            Err(LoginPageError::login_failed(LoginFailure::Internal))
+        }
+    }
+}
+
+/// Counts the outcome of a login. A failure is also sent to the installation,
+/// wherever it is connected, since the login page may be closed by now.
+async fn report_login(
+    installation_id: Uuid,
+    result: Result<(), anyhow::Error>,
+) -> Result<(), LoginFailure> {
+    let failure = match result {
+        Ok(()) => {
+            metrics::OAUTH_LOGINS.with_label_values(&["success"]).inc();
+            return Ok(());
+        }
+        Err(err) => {
+            warn!(error = ?err, "Error logging in");
+            login_failure(&err)
+        }
+    };
+    metrics::OAUTH_LOGINS.with_label_values(&["failure"]).inc();
+    let response = ServerResponse::LoginFailed { reason: failure };
+    if let Err(err) = pubsub::send_to_installation(installation_id, response).await {
+        error!(error = ?err, "Error sending login failure");
+    }
+    Err(failure)
+}
+
+async fn login(
+    provider: &dyn IdentityProvider,
+    installation_id: Uuid,
+    access_token: String,
+) -> Result<(), anyhow::Error> {
+    let identity = provider.identify(&access_token).await?;
 
     let pg = pg();
     let mut tx = pg.begin().await?;
-    let account = sqlx::query!(
-        "SELECT account_lookup($1, $2) as account_id",
-        response.user.id,
-        response.user.username
+    let account = metrics::time_query(
+        "account_lookup",
+        sqlx::query!(
+            "SELECT account_lookup($1, $2, $3, $4) as account_id",
+            provider.name(),
+            identity.external_id,
+            identity.username,
+            access_token,
+        )
+        .fetch_one(&mut tx),
+    )
+    .await?;
+
+    metrics::time_query(
+        "installation_login",
+        sqlx::query!(
+            "SELECT installation_login($1, $2) as rows_changed",
+            installation_id,
+            account.account_id,
+        )
+        .fetch_one(&mut tx),
     )
-    .fetch_one(&mut tx)
-    .await
-    .expect("Function should always return a value");
-
-    sqlx::query!(
-        "SELECT installation_login($1, $2, $3) as rows_changed",
-        installation_id,
-        account.account_id,
-        access_token,
+    .await?;
+    let profile = metrics::time_query(
+        "installation_profile",
+        sqlx::query_as!(
+            UserProfile,
+            "SELECT id, username FROM installation_profile($1)",
+            installation_id,
+        )
+        .fetch_one(&mut tx),
     )
-    .fetch_one(&mut tx)
     .await?;
     tx.commit().await?;
 
-    Ok(())
+    // Logging in can change the account's username, so every installation
+    // logged into it is told, not just this one.
+    pubsub::send_to_account(profile.id, ServerResponse::Authenticated { profile }).await
 }
 
-fn env(var: &str) -> String {
-    std::env::var(var).unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OAuthConfig;
+    use crate::connected_clients::CONNECTED_CLIENTS;
+    use crate::itchio::Itchio;
+    use crate::outbox::{Outbox, OutboxSettings};
+    use std::time::Duration;
+
+    /// Login settings for a player only the fake itch.io knows, and the
+    /// player's username. itch.io ids and usernames are unique, so each login
+    /// needs a new player.
+    fn new_player(flow: OAuthFlow) -> (LoginSettings, String) {
+        let player_id = (Uuid::new_v4().as_u128() >> 65) as i64;
+        let username = format!("player{}", player_id);
+        let settings = LoginSettings {
+            provider: Arc::new(Itchio::new(
+                &OAuthConfig {
+                    client_secret: fake_itchio::CLIENT_SECRET.to_owned(),
+                    flow,
+                    itchio_url: fake_itchio::start(player_id, &username),
+                    ..OAuthConfig::default()
+                },
+                Url::parse(fake_itchio::REDIRECT_URI).unwrap(),
+            )),
+            flow,
+            receive_token_url: Url::parse("https://cantina.example.com/auth/receive_token")
+                .unwrap(),
+        };
+        (settings, username)
+    }
+
+    /// A new installation with a session on this server.
+    async fn connect() -> Result<(Uuid, Arc<Outbox>), anyhow::Error> {
+        let installation_id = Uuid::new_v4();
+        sqlx::query!("SELECT * FROM installation_lookup($1)", installation_id)
+            .fetch_one(&pg())
+            .await?;
+        let outbox = Outbox::new(OutboxSettings::default());
+        CONNECTED_CLIENTS.connect(installation_id, outbox.clone());
+        Ok((installation_id, outbox))
+    }
+
+    /// Posts the login page's form, returning the status and JSON body.
+    async fn post_token(settings: &LoginSettings, form: &str) -> (StatusCode, serde_json::Value) {
+        let response = warp::test::request()
+            .method("POST")
+            .path("/auth/receive_token")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(form)
+            .reply(&login_routes(settings.clone()))
+            .await;
+        let body = serde_json::from_slice(response.body()).unwrap();
+        (response.status(), body)
+    }
+
+    /// Follows itch.io's redirect back to the server, returning the status
+    /// and the page.
+    async fn follow_redirect(settings: &LoginSettings, query: &str) -> (StatusCode, String) {
+        let response = warp::test::request()
+            .path(&format!("/auth/itchio_callback?{}", query))
+            .reply(&login_routes(settings.clone()))
+            .await;
+        let page = String::from_utf8_lossy(response.body()).into_owned();
+        (response.status(), page)
+    }
+
+    /// The next login outcome sent to `outbox`.
+    async fn login_outcome(outbox: &Outbox) -> Result<ServerResponse, anyhow::Error> {
+        let outcome = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match outbox.recv().await {
+                    Ok(envelope) => match envelope.response {
+                        response @ ServerResponse::Authenticated { .. }
+                        | response @ ServerResponse::LoginFailed { .. } => return response,
+                        _ => {}
+                    },
+                    Err(reason) => panic!("Outbox closed: {:?}", reason),
+                }
+            }
+        })
+        .await?;
+        Ok(outcome)
+    }
+
+    /// Logs in through the fake itch.io with each flow, all the way from the
+    /// login page to the client being told who it is. Needs the database from
+    /// `.env`, like the migrations tests. The flows share a test so that only
+    /// one notification listener delivers to `CONNECTED_CLIENTS`.
+    #[tokio::test]
+    async fn logins_reach_client() -> Result<(), anyhow::Error> {
+        dotenv::dotenv().ok();
+        migrations::run_all().await?;
+        tokio::spawn(pubsub::pg_notify_loop());
+        while !health::listener_alive() {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+
+        token_login_reaches_client().await?;
+        code_login_reaches_client().await
+    }
+
+    async fn token_login_reaches_client() -> Result<(), anyhow::Error> {
+        let (settings, username) = new_player(OAuthFlow::Implicit);
+        let (installation_id, outbox) = connect().await?;
+
+        // A token itch.io doesn't recognize is reported to both the page and
+        // the client.
+        let state = oauth_state::create(installation_id, 60).await?;
+        let forged = format!("state={}&access_token=forged", state);
+        let (status, body) = post_token(&settings, &forged).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "rejected");
+        assert!(matches!(
+            login_outcome(&outbox).await?,
+            ServerResponse::LoginFailed {
+                reason: LoginFailure::Rejected
+            }
+        ));
+
+        let state = oauth_state::create(installation_id, 60).await?;
+        let login_form = format!("state={}&access_token={}", state, fake_itchio::ACCESS_TOKEN);
+        let (status, body) = post_token(&settings, &login_form).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["message"], LOGGED_IN_MESSAGE);
+        match login_outcome(&outbox).await? {
+            ServerResponse::Authenticated { profile } => assert_eq!(profile.username, username),
+            other => panic!("Expected to be authenticated, got {:?}", other),
+        }
+
+        // The state was used up by the first login, and fields are required.
+        let (status, body) = post_token(&settings, &login_form).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "invalid_state");
+        let (status, body) = post_token(&settings, &format!("state={}", state)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "missing_field");
+        Ok(())
+    }
+
+    async fn code_login_reaches_client() -> Result<(), anyhow::Error> {
+        let (settings, username) = new_player(OAuthFlow::Code);
+        let (installation_id, outbox) = connect().await?;
+
+        // A code itch.io didn't issue is reported to both the page and the client.
+        let state = oauth_state::create(installation_id, 60).await?;
+        let (status, _) = follow_redirect(&settings, &format!("state={}&code=forged", state)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(matches!(
+            login_outcome(&outbox).await?,
+            ServerResponse::LoginFailed {
+                reason: LoginFailure::Rejected
+            }
+        ));
+
+        let state = oauth_state::create(installation_id, 60).await?;
+        let callback = format!("state={}&code={}", state, fake_itchio::CODE);
+        let (status, page) = follow_redirect(&settings, &callback).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(page.contains(LOGGED_IN_MESSAGE));
+        match login_outcome(&outbox).await? {
+            ServerResponse::Authenticated { profile } => assert_eq!(profile.username, username),
+            other => panic!("Expected to be authenticated, got {:?}", other),
+        }
+
+        let (status, page) = follow_redirect(&settings, &callback).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(page.contains(LoginPageError::invalid_state().message));
+
+        // Tokens can't be posted when the server fetches them itself.
+        let (status, body) = post_token(&settings, "state=a&access_token=b").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "wrong_flow");
+        Ok(())
+    }
 }
@@ -0,0 +1,101 @@
+use crate::connected_clients::CONNECTED_CLIENTS;
+use crate::outbox::OUTBOX_STATS;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+use std::future::Future;
+use tracing::{debug_span, error};
+use tracing_futures::Instrument;
+use warp::http::header::CONTENT_TYPE;
+
+lazy_static! {
+    pub static ref WEBSOCKETS_CONNECTED: IntGauge =
+        register_int_gauge!("cantina_websockets_connected", "Open websocket connections").unwrap();
+    static ref SESSIONS: IntGauge = register_int_gauge!(
+        "cantina_sessions",
+        "Sessions on this server, including disconnected ones that can still be resumed"
+    )
+    .unwrap();
+    static ref AUTHENTICATED_ACCOUNTS: IntGauge = register_int_gauge!(
+        "cantina_authenticated_accounts",
+        "Distinct accounts logged in on this server"
+    )
+    .unwrap();
+    static ref OUTBOX_QUEUED: IntGauge = register_int_gauge!(
+        "cantina_outbox_queued_messages",
+        "Messages waiting to be written to websockets"
+    )
+    .unwrap();
+    static ref OUTBOX_MAX_DEPTH: IntGauge = register_int_gauge!(
+        "cantina_outbox_max_depth",
+        "The most messages any one outbox has held"
+    )
+    .unwrap();
+    pub static ref OUTBOX_OVERFLOWS: IntCounterVec = register_int_counter_vec!(
+        "cantina_outbox_overflows_total",
+        "Messages pushed to a full outbox, by what gave way: dropped, coalesced or disconnected",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "cantina_requests_total",
+        "Websocket requests handled, by kind and outcome",
+        &["kind", "outcome"]
+    )
+    .unwrap();
+    pub static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "cantina_request_duration_seconds",
+        "Time spent handling websocket requests, by kind",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref DECODE_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "cantina_decode_failures_total",
+        "Websocket messages that couldn't be decoded, by wire format",
+        &["format"]
+    )
+    .unwrap();
+    static ref POSTGRES_QUERY_DURATION: HistogramVec = register_histogram_vec!(
+        "cantina_postgres_query_duration_seconds",
+        "Time spent waiting on Postgres queries, by query",
+        &["query"]
+    )
+    .unwrap();
+    pub static ref OAUTH_LOGINS: IntCounterVec = register_int_counter_vec!(
+        "cantina_oauth_logins_total",
+        "itch.io logins, by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+}
+
+/// Awaits `query` in a span named after it, recording how long it took
+/// under `name`.
+pub async fn time_query<F: Future>(name: &'static str, query: F) -> F::Output {
+    let timer = POSTGRES_QUERY_DURATION
+        .with_label_values(&[name])
+        .start_timer();
+    let output = query
+        .instrument(debug_span!("postgres", query = name))
+        .await;
+    timer.observe_duration();
+    output
+}
+
+/// `/metrics`, in the Prometheus text format.
+pub fn render() -> impl warp::Reply {
+    SESSIONS.set(CONNECTED_CLIENTS.session_count() as i64);
+    AUTHENTICATED_ACCOUNTS.set(CONNECTED_CLIENTS.account_count() as i64);
+    let outbox = OUTBOX_STATS.snapshot();
+    OUTBOX_QUEUED.set(outbox.queued as i64);
+    OUTBOX_MAX_DEPTH.set(outbox.max_depth as i64);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!(error = %err, "Error encoding metrics");
+    }
+    warp::reply::with_header(buffer, CONTENT_TYPE, encoder.format_type())
+}
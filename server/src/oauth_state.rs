@@ -0,0 +1,41 @@
+use crate::metrics;
+use migrations::{pg, sqlx};
+use std::convert::TryFrom;
+use uuid::Uuid;
+
+/// Creates the OAuth `state` for a login by `installation_id`. Whoever posts
+/// it back to `receive_token` logs that installation in, so it has to be
+/// unguessable, and it can only be used once before `lifetime_secs` runs out.
+pub async fn create(installation_id: Uuid, lifetime_secs: u32) -> Result<String, anyhow::Error> {
+    let state = format!(
+        "{}{}",
+        Uuid::new_v4().to_simple(),
+        Uuid::new_v4().to_simple()
+    );
+    let lifetime_secs = i32::try_from(lifetime_secs).unwrap_or(i32::MAX);
+    let pool = pg();
+    metrics::time_query(
+        "oauth_state_create",
+        sqlx::query!(
+            "SELECT oauth_state_create($1, $2, $3) as state",
+            state,
+            installation_id,
+            lifetime_secs
+        )
+        .fetch_one(&pool),
+    )
+    .await?;
+    Ok(state)
+}
+
+/// The installation `state` was created for, or `None` if it is unknown,
+/// expired or has already been consumed.
+pub async fn consume(state: &str) -> Result<Option<Uuid>, anyhow::Error> {
+    let pool = pg();
+    let consumed = metrics::time_query(
+        "oauth_state_consume",
+        sqlx::query!("SELECT oauth_state_consume($1) as installation_id", state).fetch_one(&pool),
+    )
+    .await?;
+    Ok(consumed.installation_id)
+}
@@ -0,0 +1,394 @@
+use crate::metrics;
+use lazy_static::lazy_static;
+use serde::Deserializer;
+use serde_derive::Deserialize;
+use shared::ResponseEnvelope;
+use std::{
+    collections::VecDeque,
+    mem::discriminant,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::Notify;
+use tracing::info;
+
+lazy_static! {
+    pub static ref OUTBOX_STATS: OutboxStats = OutboxStats::default();
+}
+
+/// What to do when a client isn't reading its messages fast enough to keep
+/// its outbox under `OutboxSettings::capacity`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued message.
+    DropOldest,
+    /// Replace a queued unsolicited message of the same kind, such as an
+    /// earlier `Ping`, falling back to discarding the oldest message.
+    Coalesce,
+    /// Close the connection. The client can resume its session once it
+    /// reconnects.
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "coalesce" => Ok(OverflowPolicy::Coalesce),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!(
+                "Invalid overflow policy `{}`, expected `drop-oldest`, `coalesce` or `disconnect`",
+                s
+            )),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for OverflowPolicy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value: String = serde::Deserialize::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboxSettings {
+    pub capacity: usize,
+    #[serde(rename = "overflow_policy")]
+    pub policy: OverflowPolicy,
+}
+
+impl Default for OutboxSettings {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            policy: OverflowPolicy::Coalesce,
+        }
+    }
+}
+
+/// Totals across every outbox on this server.
+#[derive(Default)]
+pub struct OutboxStats {
+    queued: AtomicUsize,
+    max_depth: AtomicUsize,
+    dropped: AtomicU64,
+    coalesced: AtomicU64,
+    overflow_disconnects: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OutboxStatsSnapshot {
+    /// Messages currently waiting to be written to a websocket.
+    pub queued: usize,
+    /// The deepest any single outbox has been.
+    pub max_depth: usize,
+    pub dropped: u64,
+    pub coalesced: u64,
+    pub overflow_disconnects: u64,
+}
+
+impl OutboxStats {
+    pub fn snapshot(&self) -> OutboxStatsSnapshot {
+        OutboxStatsSnapshot {
+            queued: self.queued.load(Ordering::Relaxed),
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            overflow_disconnects: self.overflow_disconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Periodically logs `OUTBOX_STATS`.
+pub async fn log_stats_loop() {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        let stats = OUTBOX_STATS.snapshot();
+        info!(
+            queued = stats.queued,
+            max_depth = stats.max_depth,
+            dropped = stats.dropped,
+            coalesced = stats.coalesced,
+            overflow_disconnects = stats.overflow_disconnects,
+            "Outbox stats"
+        );
+    }
+}
+
+/// A bounded queue of messages waiting to be written to one client's
+/// websocket. Pushing never blocks: when the queue is full, the outbox's
+/// `OverflowPolicy` decides what gives.
+pub struct Outbox {
+    state: Mutex<OutboxState>,
+    notify: Notify,
+    settings: OutboxSettings,
+    stats: &'static OutboxStats,
+}
+
+struct OutboxState {
+    queue: VecDeque<ResponseEnvelope>,
+    closed: Option<CloseReason>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloseReason {
+    Closed,
+    Overflowed,
+}
+
+impl Outbox {
+    pub fn new(settings: OutboxSettings) -> Arc<Self> {
+        Self::with_stats(settings, &OUTBOX_STATS)
+    }
+
+    fn with_stats(settings: OutboxSettings, stats: &'static OutboxStats) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(OutboxState {
+                queue: VecDeque::with_capacity(settings.capacity.min(16)),
+                closed: None,
+            }),
+            notify: Notify::new(),
+            settings,
+            stats,
+        })
+    }
+
+    /// Queues `envelope` to be sent. Messages pushed after the outbox is
+    /// closed are discarded.
+    pub fn push(&self, envelope: ResponseEnvelope) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed.is_some() {
+            return;
+        }
+
+        if state.queue.len() >= self.settings.capacity {
+            match self.settings.policy {
+                OverflowPolicy::DropOldest => {
+                    state.queue.pop_front();
+                    self.count_overflow(&self.stats.dropped, "dropped");
+                }
+                OverflowPolicy::Coalesce => {
+                    if let Some(index) = coalesce_index(&state.queue, &envelope) {
+                        state.queue[index] = envelope;
+                        self.count_overflow(&self.stats.coalesced, "coalesced");
+                        return;
+                    }
+                    state.queue.pop_front();
+                    self.count_overflow(&self.stats.dropped, "dropped");
+                }
+                OverflowPolicy::Disconnect => {
+                    self.count_overflow(&self.stats.overflow_disconnects, "disconnected");
+                    self.close_locked(&mut state, CloseReason::Overflowed);
+                    return;
+                }
+            }
+            self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        state.queue.push_back(envelope);
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .max_depth
+            .fetch_max(state.queue.len(), Ordering::Relaxed);
+        drop(state);
+        self.notify.notify();
+    }
+
+    fn count_overflow(&self, total: &AtomicU64, outcome: &str) {
+        total.fetch_add(1, Ordering::Relaxed);
+        metrics::OUTBOX_OVERFLOWS
+            .with_label_values(&[outcome])
+            .inc();
+    }
+
+    /// Waits for the next message. Returns `Err` once the outbox is closed
+    /// and every message queued before closing has been received.
+    pub async fn recv(&self) -> Result<ResponseEnvelope, CloseReason> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(envelope) = state.queue.pop_front() {
+                    self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                    return Ok(envelope);
+                }
+                if let Some(reason) = state.closed {
+                    return Err(reason);
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Stops accepting messages. Messages already queued are still received.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed.is_none() {
+            state.closed = Some(CloseReason::Closed);
+            drop(state);
+            self.notify.notify();
+        }
+    }
+
+    /// Discards everything queued, so the client is disconnected promptly.
+    fn close_locked(&self, state: &mut OutboxState, reason: CloseReason) {
+        self.stats
+            .queued
+            .fetch_sub(state.queue.len(), Ordering::Relaxed);
+        state.queue.clear();
+        state.closed = Some(reason);
+        self.notify.notify();
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().queue.len()
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        self.stats
+            .queued
+            .fetch_sub(state.queue.len(), Ordering::Relaxed);
+    }
+}
+
+/// Finds a queued unsolicited message that `envelope` makes redundant.
+/// Responses to requests are never coalesced.
+fn coalesce_index(
+    queue: &VecDeque<ResponseEnvelope>,
+    envelope: &ResponseEnvelope,
+) -> Option<usize> {
+    if envelope.request_id.is_some() {
+        return None;
+    }
+
+    let kind = discriminant(&envelope.response);
+    queue
+        .iter()
+        .position(|queued| queued.request_id.is_none() && discriminant(&queued.response) == kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{ErrorCode, ServerResponse};
+
+    fn outbox(capacity: usize, policy: OverflowPolicy) -> Arc<Outbox> {
+        // Leaked so each test's totals are independent of the others.
+        let stats = Box::leak(Box::new(OutboxStats::default()));
+        Outbox::with_stats(OutboxSettings { capacity, policy }, stats)
+    }
+
+    fn unsolicited(response: ServerResponse) -> ResponseEnvelope {
+        ResponseEnvelope {
+            request_id: None,
+            response,
+        }
+    }
+
+    fn drain(outbox: &Outbox) -> Vec<ResponseEnvelope> {
+        outbox.close();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut received = Vec::new();
+        while let Ok(envelope) = runtime.block_on(outbox.recv()) {
+            received.push(envelope);
+        }
+        received
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let outbox = outbox(2, OverflowPolicy::DropOldest);
+        for request_id in 0..3 {
+            outbox.push(ResponseEnvelope {
+                request_id: Some(request_id),
+                response: ServerResponse::Pong,
+            });
+        }
+
+        let ids = drain(&outbox)
+            .into_iter()
+            .map(|envelope| envelope.request_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![Some(1), Some(2)]);
+        assert_eq!(outbox.stats.snapshot().dropped, 1);
+        assert_eq!(outbox.stats.snapshot().queued, 0);
+    }
+
+    #[test]
+    fn coalesce() {
+        let outbox = outbox(2, OverflowPolicy::Coalesce);
+        outbox.push(unsolicited(ServerResponse::Ping));
+        outbox.push(unsolicited(ServerResponse::Error {
+            code: ErrorCode::Timeout,
+        }));
+        outbox.push(unsolicited(ServerResponse::Error {
+            code: ErrorCode::Internal,
+        }));
+
+        let received = drain(&outbox);
+        assert_eq!(received.len(), 2);
+        assert!(matches!(received[0].response, ServerResponse::Ping));
+        assert!(matches!(
+            received[1].response,
+            ServerResponse::Error {
+                code: ErrorCode::Internal
+            }
+        ));
+        assert_eq!(outbox.stats.snapshot().coalesced, 1);
+
+        // Nothing to coalesce with, so the oldest message gives way.
+        let outbox = self::outbox(1, OverflowPolicy::Coalesce);
+        outbox.push(unsolicited(ServerResponse::Ping));
+        outbox.push(unsolicited(ServerResponse::Pong));
+        let received = drain(&outbox);
+        assert!(matches!(
+            received[..],
+            [ResponseEnvelope {
+                response: ServerResponse::Pong,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn disconnect() {
+        let outbox = outbox(1, OverflowPolicy::Disconnect);
+        outbox.push(unsolicited(ServerResponse::Ping));
+        outbox.push(unsolicited(ServerResponse::Ping));
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        assert_eq!(
+            runtime.block_on(outbox.recv()).unwrap_err(),
+            CloseReason::Overflowed
+        );
+        assert_eq!(outbox.stats.snapshot().overflow_disconnects, 1);
+        assert_eq!(outbox.stats.snapshot().queued, 0);
+    }
+
+    #[test]
+    fn recv_waits_for_push() {
+        let outbox = outbox(4, OverflowPolicy::DropOldest);
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let received = runtime.block_on(async {
+            let receiver = tokio::spawn({
+                let outbox = outbox.clone();
+                async move { outbox.recv().await }
+            });
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+            outbox.push(unsolicited(ServerResponse::Pong));
+            receiver.await.unwrap()
+        });
+        assert!(matches!(received.unwrap().response, ServerResponse::Pong));
+    }
+}
@@ -0,0 +1,47 @@
+use semver::Version;
+
+// The range of client protocol versions this server accepts, inclusive.
+// 0.1.0 wrapped every message in an envelope. Older clients send bare requests
+// that fail to decode, so they're never told to update: their requests are
+// ignored until the heartbeat timeout drops them.
+pub const MINIMUM_CLIENT_VERSION: &str = "0.1.0";
+pub const MAXIMUM_CLIENT_VERSION: &str = shared::PROTOCOL_VERSION;
+
+#[derive(Debug, PartialEq)]
+pub enum VersionCheck {
+    Supported,
+    UpdateRequired,
+    TooNew,
+}
+
+pub fn check_client_version(version: &str) -> VersionCheck {
+    let minimum = Version::parse(MINIMUM_CLIENT_VERSION).expect("Invalid minimum client version");
+    let maximum = Version::parse(MAXIMUM_CLIENT_VERSION).expect("Invalid maximum client version");
+
+    // Clients old enough to not send a valid version definitely need updating.
+    match Version::parse(version) {
+        Ok(version) if version < minimum => VersionCheck::UpdateRequired,
+        Ok(version) if version > maximum => VersionCheck::TooNew,
+        Ok(_) => VersionCheck::Supported,
+        Err(_) => VersionCheck::UpdateRequired,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_client_versions() {
+        assert_eq!(
+            check_client_version(shared::PROTOCOL_VERSION),
+            VersionCheck::Supported
+        );
+        assert_eq!(check_client_version("0.0.1"), VersionCheck::UpdateRequired);
+        assert_eq!(
+            check_client_version("garbage"),
+            VersionCheck::UpdateRequired
+        );
+        assert_eq!(check_client_version("99.0.0"), VersionCheck::TooNew);
+    }
+}
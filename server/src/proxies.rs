@@ -0,0 +1,155 @@
+use serde::Deserializer;
+use std::{net::IpAddr, str::FromStr};
+
+/// A range of addresses, written as `address/prefix` or as a bare address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix: u32,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid network `{}`, expected `address/prefix`", s);
+        let mut parts = s.trim().splitn(2, '/');
+        let address = parts
+            .next()
+            .and_then(|address| address.parse::<IpAddr>().ok())
+            .ok_or_else(invalid)?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix
+                .parse::<u32>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(invalid)?,
+            None => max_prefix,
+        };
+        Ok(Self { address, prefix })
+    }
+}
+
+/// The proxies allowed to tell the server who a client is with
+/// `X-Forwarded-For`. Written as a comma separated list of networks, e.g.
+/// `10.0.0.0/8, 192.168.1.1`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrustedProxies {
+    networks: Vec<IpNetwork>,
+}
+
+impl TrustedProxies {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        self.networks
+            .iter()
+            .any(|network| network.contains(address))
+    }
+
+    /// The address of the client behind `peer`. Each trusted proxy appends
+    /// the address it received the request from to `X-Forwarded-For`, so the
+    /// header is followed from the end for as long as the hops are trusted.
+    /// Anything before the first untrusted hop could have been made up.
+    pub fn client_address(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        let mut client = peer;
+        if let Some(forwarded_for) = forwarded_for {
+            for hop in forwarded_for.rsplit(',') {
+                if !self.contains(client) {
+                    break;
+                }
+                match hop.trim().parse() {
+                    Ok(hop) => client = hop,
+                    Err(_) => break,
+                }
+            }
+        }
+        client
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let networks = s
+            .split(',')
+            .filter(|network| !network.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { networks })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for TrustedProxies {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value: String = serde::Deserialize::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_trusted_proxies() {
+        let proxies = "10.0.0.0/8, 192.168.1.1,::1"
+            .parse::<TrustedProxies>()
+            .unwrap();
+        assert!(proxies.contains([10, 1, 2, 3].into()));
+        assert!(!proxies.contains([11, 0, 0, 1].into()));
+        assert!(proxies.contains([192, 168, 1, 1].into()));
+        assert!(!proxies.contains([192, 168, 1, 2].into()));
+        assert!(proxies.contains("::1".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<TrustedProxies>()
+            .unwrap()
+            .contains([1, 2, 3, 4].into()));
+        assert_eq!("".parse::<TrustedProxies>(), Ok(TrustedProxies::default()));
+        assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+        assert!("localhost".parse::<TrustedProxies>().is_err());
+    }
+
+    #[test]
+    fn forwarded_for_is_only_trusted_from_proxies() {
+        let proxies = "10.0.0.0/8".parse::<TrustedProxies>().unwrap();
+        let proxy = IpAddr::from([10, 0, 0, 1]);
+        let client = IpAddr::from([203, 0, 113, 7]);
+        let spoofed = "198.51.100.1";
+
+        // A client connecting directly can't choose its address.
+        assert_eq!(proxies.client_address(client, Some(spoofed)), client);
+        assert_eq!(
+            TrustedProxies::default().client_address(proxy, Some(spoofed)),
+            proxy
+        );
+        // Through the proxy, whatever the client sent is ignored.
+        assert_eq!(
+            proxies.client_address(proxy, Some(&format!("{}, {}", spoofed, client))),
+            client
+        );
+        // Chained proxies are followed back to the client.
+        assert_eq!(
+            proxies.client_address(proxy, Some(&format!("{}, 10.0.0.2", client))),
+            client
+        );
+        assert_eq!(proxies.client_address(proxy, None), proxy);
+        assert_eq!(proxies.client_address(proxy, Some("garbage")), proxy);
+    }
+}
@@ -1,33 +1,220 @@
-use super::websockets::CONNECTED_CLIENTS;
+use super::connected_clients::CONNECTED_CLIENTS;
+use super::health;
+use super::metrics;
+use anyhow::anyhow;
 use migrations::{pg, sqlx};
+use serde_derive::{Deserialize, Serialize};
 use shared::{ServerResponse, UserProfile};
 use sqlx::postgres::PgListener;
+use std::time::{Duration, Instant};
+use tracing::{error, info_span};
+use tracing_futures::Instrument;
 use uuid::Uuid;
 
-pub async fn pg_notify_loop() -> Result<(), anyhow::Error> {
+const SERVER_MESSAGES_CHANNEL: &str = "server_messages";
+// Postgres rejects NOTIFY payloads of 8000 bytes or more.
+const MAX_PAYLOAD_LENGTH: usize = 7999;
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+// How long the listener has to run before a failure restarts it right away.
+const STABLE_LISTENER_TIME: Duration = Duration::from_secs(60);
+
+/// Who a `ServerMessage` should be delivered to.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum MessageTarget {
+    Installation(Uuid),
+    Account(i64),
+}
+
+/// The JSON payload sent on the `server_messages` channel. Every replica
+/// listens on the channel and delivers the message to its own sockets.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerMessage {
+    pub target: MessageTarget,
+    pub response: ServerResponse,
+}
+
+/// Sends `response` to `installation_id`, regardless of which replica it is
+/// connected to.
+pub async fn send_to_installation(
+    installation_id: Uuid,
+    response: ServerResponse,
+) -> Result<(), anyhow::Error> {
+    publish(ServerMessage {
+        target: MessageTarget::Installation(installation_id),
+        response,
+    })
+    .await
+}
+
+/// Sends `response` to every installation logged into `account_id`, on
+/// every replica.
+pub async fn send_to_account(
+    account_id: i64,
+    response: ServerResponse,
+) -> Result<(), anyhow::Error> {
+    publish(ServerMessage {
+        target: MessageTarget::Account(account_id),
+        response,
+    })
+    .await
+}
+
+async fn publish(message: ServerMessage) -> Result<(), anyhow::Error> {
+    let payload = serde_json::to_string(&message)?;
+    if payload.len() > MAX_PAYLOAD_LENGTH {
+        return Err(anyhow!(
+            "Message is too large to publish ({} bytes)",
+            payload.len()
+        ));
+    }
+
+    metrics::time_query(
+        "pg_notify",
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(SERVER_MESSAGES_CHANNEL)
+            .bind(payload)
+            .execute(&pg()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Delivers a `server_messages` payload to this replica's sockets.
+fn deliver(payload: &str) -> Result<(), anyhow::Error> {
+    let message: ServerMessage = serde_json::from_str(payload)?;
+    match message.target {
+        MessageTarget::Installation(installation_id) => {
+            CONNECTED_CLIENTS.send_to_installation_id(installation_id, message.response);
+        }
+        MessageTarget::Account(account_id) => {
+            CONNECTED_CLIENTS.send_to_account(account_id, message.response);
+        }
+    }
+    Ok(())
+}
+
+/// Attaches the account to the installation's session. `login` tells the
+/// account's installations about it afterwards, and notifications are
+/// handled in order, so the installation is included.
+async fn installation_logged_in(installation_id: Uuid) -> Result<(), anyhow::Error> {
+    let profile = metrics::time_query(
+        "installation_profile",
+        sqlx::query_as!(
+            UserProfile,
+            "SELECT id, username FROM installation_profile($1)",
+            installation_id,
+        )
+        .fetch_one(&pg()),
+    )
+    .await?;
+
+    CONNECTED_CLIENTS.associate_account(installation_id, profile);
+    Ok(())
+}
+
+/// Listens for notifications for as long as the server runs. If the listener
+/// fails or panics, it is restarted with a growing delay, and `/readyz`
+/// reports the replica as not ready until it is listening again.
+/// Notifications sent in the meantime are lost.
+pub async fn pg_notify_loop() {
+    let mut delay = INITIAL_RESTART_DELAY;
+    loop {
+        let started_at = Instant::now();
+        let result = tokio::spawn(listen()).await;
+        health::set_listener_alive(false);
+        let err = match result {
+            Ok(Ok(())) => anyhow!("Stopped listening"),
+            Ok(Err(err)) => err,
+            // It panicked.
+            Err(err) => err.into(),
+        };
+        error!(error = ?err, "Postgres listener stopped");
+
+        if started_at.elapsed() >= STABLE_LISTENER_TIME {
+            delay = INITIAL_RESTART_DELAY;
+        }
+        tokio::time::delay_for(delay).await;
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+/// Only returns once listening fails.
+async fn listen() -> Result<(), anyhow::Error> {
     let pool = pg();
     let mut listener = PgListener::from_pool(&pool).await?;
-    listener.listen_all(vec!["installation_login"]).await?;
-    while let Ok(notification) = listener.recv().await {
-        if notification.channel() == "installation_login" {
+    listener
+        .listen_all(vec!["installation_login", SERVER_MESSAGES_CHANNEL])
+        .await?;
+    health::set_listener_alive(true);
+    loop {
+        let notification = listener.recv().await?;
+        let result = match notification.channel() {
             // The payload is the installation_id that logged in.
-            let installation_id = Uuid::parse_str(notification.payload())?;
-            let profile = sqlx::query_as!(
-                UserProfile,
-                "SELECT id, username FROM installation_profile($1)",
-                installation_id,
-            )
-            .fetch_one(&pool)
-            .await?;
-
-            CONNECTED_CLIENTS
-                .associate_account(installation_id, profile.id)
-                .await;
-
-            CONNECTED_CLIENTS
-                .send_to_installation_id(installation_id, ServerResponse::Authenticated { profile })
-                .await;
+            "installation_login" => match Uuid::parse_str(notification.payload()) {
+                Ok(installation_id) => {
+                    installation_logged_in(installation_id)
+                        .instrument(info_span!("installation_logged_in", %installation_id))
+                        .await
+                }
+                Err(err) => Err(err.into()),
+            },
+            SERVER_MESSAGES_CHANNEL => deliver(notification.payload()),
+            _ => Ok(()),
+        };
+
+        if let Err(err) = result {
+            error!(
+                channel = notification.channel(),
+                error = ?err,
+                "Error handling notification"
+            );
         }
     }
-    panic!("Error on postgres listening");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbox::{Outbox, OutboxSettings};
+
+    #[test]
+    fn account_messages_reach_every_installation() {
+        // Random ids, since CONNECTED_CLIENTS is shared with other tests.
+        let account_id = (Uuid::new_v4().as_u128() >> 65) as i64;
+        let installations = (0..3)
+            .map(|index| {
+                let installation_id = Uuid::new_v4();
+                let outbox = Outbox::new(OutboxSettings::default());
+                CONNECTED_CLIENTS.connect(installation_id, outbox.clone());
+                CONNECTED_CLIENTS.associate_account(
+                    installation_id,
+                    UserProfile {
+                        // The last installation is logged into another account.
+                        id: if index < 2 {
+                            account_id
+                        } else {
+                            account_id + 1
+                        },
+                        username: "ecton".to_owned(),
+                    },
+                );
+                outbox
+            })
+            .collect::<Vec<_>>();
+
+        let payload = serde_json::to_string(&ServerMessage {
+            target: MessageTarget::Account(account_id),
+            response: ServerResponse::Ping,
+        })
+        .unwrap();
+        deliver(&payload).unwrap();
+
+        let received = installations
+            .iter()
+            .map(|outbox| outbox.len())
+            .collect::<Vec<_>>();
+        assert_eq!(received, vec![1, 1, 0]);
+        assert!(deliver("{}").is_err());
+    }
 }
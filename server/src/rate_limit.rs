@@ -0,0 +1,281 @@
+use crate::config::CONFIG;
+use lazy_static::lazy_static;
+use serde::Deserializer;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+lazy_static! {
+    pub static ref RATE_LIMITER: RateLimiter = RateLimiter::new(CONFIG.rate_limits.clone());
+}
+
+/// Allows bursts of up to `burst` requests, refilling one request every
+/// `period / burst`. Written as `burst/seconds`, e.g. `10/60`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    pub const fn new(burst: u32, period: Duration) -> Self {
+        Self { burst, period }
+    }
+
+    /// Enough for a client heartbeating every `interval`: twice the
+    /// expected rate, over the time it takes to miss every heartbeat.
+    pub fn for_heartbeats(interval: Duration) -> Self {
+        Self::new(
+            2 * shared::MISSED_HEARTBEAT_LIMIT,
+            shared::heartbeat_timeout(interval),
+        )
+    }
+
+    fn refill_per_second(&self) -> f64 {
+        self.burst as f64 / self.period.as_secs_f64()
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let burst = parts
+            .next()
+            .and_then(|burst| burst.trim().parse::<u32>().ok())
+            .filter(|burst| *burst > 0);
+        let seconds = parts
+            .next()
+            .and_then(|seconds| seconds.trim().parse::<u64>().ok())
+            .filter(|seconds| *seconds > 0);
+        match (burst, seconds) {
+            (Some(burst), Some(seconds)) => Ok(RateLimit::new(burst, Duration::from_secs(seconds))),
+            _ => Err(format!(
+                "Invalid rate limit `{}`, expected `requests/seconds`",
+                s
+            )),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for RateLimit {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value: String = serde::Deserialize::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// The rate limit for each kind of `ServerRequest`, keyed by `ServerRequest::kind()`.
+/// Kinds without a limit are never limited. Heartbeats are limited by
+/// `limit_heartbeats` unless a limit is set for them.
+#[derive(Clone, Debug)]
+pub struct RateLimits {
+    limits: HashMap<&'static str, RateLimit>,
+}
+
+pub const REQUEST_KINDS: [&str; 4] = ["authenticate", "authentication_url", "ping", "pong"];
+const HEARTBEAT_KINDS: [&str; 2] = ["ping", "pong"];
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        let mut limits = HashMap::new();
+        limits.insert("authenticate", RateLimit::new(10, Duration::from_secs(60)));
+        limits.insert(
+            "authentication_url",
+            RateLimit::new(5, Duration::from_secs(60)),
+        );
+        Self { limits }
+    }
+}
+
+/// Deserializes from a table of overrides, e.g. `authenticate = "10/60"`.
+impl<'de> serde::Deserialize<'de> for RateLimits {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let overrides: HashMap<String, RateLimit> = serde::Deserialize::deserialize(deserializer)?;
+        let mut limits = Self::default();
+        for (kind, limit) in overrides {
+            limits.set(&kind, limit).map_err(serde::de::Error::custom)?;
+        }
+        Ok(limits)
+    }
+}
+
+impl RateLimits {
+    /// Overrides the limit for `kind`, which must be one of `REQUEST_KINDS`.
+    pub fn set(&mut self, kind: &str, limit: RateLimit) -> Result<(), String> {
+        match REQUEST_KINDS.iter().find(|known| **known == kind) {
+            Some(kind) => {
+                self.limits.insert(kind, limit);
+                Ok(())
+            }
+            None => Err(format!(
+                "Unknown request kind `{}`, expected one of {}",
+                kind,
+                REQUEST_KINDS.join(", ")
+            )),
+        }
+    }
+
+    /// Limits heartbeats that don't have a limit yet to
+    /// `RateLimit::for_heartbeats(interval)`.
+    pub fn limit_heartbeats(&mut self, interval: Duration) {
+        for kind in HEARTBEAT_KINDS.iter() {
+            self.limits
+                .entry(kind)
+                .or_insert_with(|| RateLimit::for_heartbeats(interval));
+        }
+    }
+
+    pub fn get(&self, kind: &str) -> Option<RateLimit> {
+        self.limits.get(kind).copied()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum RateLimitKey {
+    Installation(Uuid),
+    RemoteAddress(IpAddr),
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.refill_per_second()).min(limit.burst as f64);
+        self.updated_at = now;
+    }
+
+    fn retry_after(&self, limit: RateLimit) -> Option<Duration> {
+        if self.tokens >= 1.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                (1.0 - self.tokens) / limit.refill_per_second(),
+            ))
+        }
+    }
+}
+
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<(RateLimitKey, &'static str), TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `kind` from the bucket of every key. If any bucket is
+    /// empty, nothing is taken and the time until a retry could succeed is
+    /// returned.
+    pub fn check(&self, kind: &'static str, keys: &[RateLimitKey]) -> Result<(), Duration> {
+        let limit = match self.limits.get(kind) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let mut retry_after = None;
+        for key in keys {
+            let bucket = buckets.entry((*key, kind)).or_insert_with(|| TokenBucket {
+                tokens: limit.burst as f64,
+                updated_at: now,
+            });
+            bucket.refill(limit, now);
+            retry_after = retry_after.max(bucket.retry_after(limit));
+        }
+
+        match retry_after {
+            Some(retry_after) => Err(retry_after),
+            None => {
+                for key in keys {
+                    if let Some(bucket) = buckets.get_mut(&(*key, kind)) {
+                        bucket.tokens -= 1.0;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Forgets buckets that have refilled completely, since they behave the
+    /// same as a new bucket.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let limits = &self.limits;
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|(_, kind), bucket| match limits.get(kind) {
+            Some(limit) => {
+                bucket.refill(limit, now);
+                bucket.tokens < limit.burst as f64
+            }
+            None => false,
+        });
+    }
+}
+
+pub async fn prune_loop() {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        RATE_LIMITER.prune();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rate_limit() {
+        assert_eq!(
+            "10/60".parse::<RateLimit>(),
+            Ok(RateLimit::new(10, Duration::from_secs(60)))
+        );
+        assert!("0/60".parse::<RateLimit>().is_err());
+        assert!("10".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn limits_every_key() {
+        let mut limits = RateLimits::default();
+        limits
+            .set("authenticate", RateLimit::new(2, Duration::from_secs(60)))
+            .unwrap();
+        let limiter = RateLimiter::new(limits);
+        let address = RateLimitKey::RemoteAddress([127, 0, 0, 1].into());
+        let first = RateLimitKey::Installation(Uuid::new_v4());
+        let second = RateLimitKey::Installation(Uuid::new_v4());
+
+        assert!(limiter.check("authenticate", &[address, first]).is_ok());
+        assert!(limiter.check("authenticate", &[address, first]).is_ok());
+        let retry_after = limiter
+            .check("authenticate", &[address, first])
+            .unwrap_err();
+        assert!(retry_after <= Duration::from_secs(30));
+        // The address is exhausted even though the second installation isn't.
+        assert!(limiter.check("authenticate", &[address, second]).is_err());
+        // A rejected check doesn't use up the second installation's tokens.
+        assert!(limiter.check("authenticate", &[second]).is_ok());
+        assert!(limiter.check("authenticate", &[second]).is_ok());
+        // Other kinds of requests have their own buckets.
+        assert!(limiter
+            .check("authentication_url", &[address, first])
+            .is_ok());
+    }
+}
@@ -0,0 +1,50 @@
+use crate::connected_clients::CONNECTED_CLIENTS;
+use crate::outbox::OUTBOX_STATS;
+use shared::ServerResponse;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+// How long clients should wait before reconnecting, giving the load balancer
+// time to stop routing to this replica.
+const RECONNECT_AFTER: Duration = Duration::from_secs(5);
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Resolves once the process receives SIGTERM or SIGINT, after telling every
+/// connected client that the server is going away. Used as the graceful
+/// shutdown signal for the HTTP server, so new connections stop being
+/// accepted once it resolves.
+pub async fn signal_received() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Error installing SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+    }
+
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    CONNECTED_CLIENTS.broadcast(ServerResponse::ServerShuttingDown {
+        reconnect_after: RECONNECT_AFTER,
+    });
+}
+
+/// Waits for messages queued for connected clients to be sent, giving up
+/// after `DRAIN_TIMEOUT`.
+pub async fn drain_outboxes() {
+    let started_at = Instant::now();
+    while started_at.elapsed() < DRAIN_TIMEOUT {
+        if OUTBOX_STATS.snapshot().queued == 0 {
+            return;
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+    warn!("Gave up waiting for outboxes to drain");
+}
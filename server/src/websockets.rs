@@ -1,226 +1,464 @@
-use super::env;
-use async_std::sync::RwLock;
-use crossbeam::channel::{unbounded, Sender};
-use futures::{executor::block_on, SinkExt, StreamExt};
-use lazy_static::lazy_static;
+use crate::config::CONFIG;
+use crate::connected_clients::{DisconnectReason, CONNECTED_CLIENTS};
+use crate::errors::{error_code, RequestError};
+use crate::identity::IDENTITY_PROVIDER;
+use crate::metrics;
+use crate::oauth_state;
+use crate::outbox::{CloseReason, Outbox};
+use crate::protocol::{check_client_version, VersionCheck, MINIMUM_CLIENT_VERSION};
+use crate::rate_limit::{RateLimitKey, RATE_LIMITER};
+use futures::{SinkExt, StreamExt};
 use migrations::{pg, sqlx};
-use shared::{Installation, ServerRequest, ServerResponse, UserProfile};
-use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
+use serde_derive::Deserialize;
+use shared::{
+    Codec, ErrorCode, Installation, RequestEnvelope, RequestId, ResponseEnvelope, ServerRequest,
+    ServerResponse, UserProfile, WireFormat,
 };
-use url::Url;
+use std::{net::IpAddr, sync::Arc, time::Instant};
+use tracing::{debug, debug_span, error, field, info, info_span, warn, Span};
+use tracing_futures::Instrument;
 use uuid::Uuid;
 use warp::filters::ws::{Message, WebSocket};
 
-lazy_static! {
-    pub static ref CONNECTED_CLIENTS: ConnectedClients = { ConnectedClients::default() };
+/// Sends responses for a single request, tagging each one with the request's id.
+#[derive(Clone)]
+pub struct Responder {
+    request_id: Option<RequestId>,
+    outbox: Arc<Outbox>,
 }
 
-pub struct ConnectedClients {
-    senders: Arc<RwLock<HashMap<Uuid, Sender<ServerResponse>>>>,
-    installations_by_account: Arc<RwLock<HashMap<i64, HashSet<Uuid>>>>,
-    account_by_installation: Arc<RwLock<HashMap<Uuid, i64>>>,
-}
-
-impl Default for ConnectedClients {
-    fn default() -> Self {
-        Self {
-            senders: Arc::new(RwLock::new(HashMap::new())),
-            installations_by_account: Arc::new(RwLock::new(HashMap::new())),
-            account_by_installation: Arc::new(RwLock::new(HashMap::new())),
-        }
+impl Responder {
+    fn send(&self, response: ServerResponse) {
+        self.outbox.push(ResponseEnvelope {
+            request_id: self.request_id,
+            response,
+        });
     }
 }
 
-impl ConnectedClients {
-    pub async fn connect(&self, installation_id: Uuid, sender: Sender<ServerResponse>) {
-        let mut senders = self.senders.write().await;
-        senders.insert(installation_id, sender);
-    }
+pub struct ConnectedClient {
+    installation_id: Option<Uuid>,
+    remote_address: Option<IpAddr>,
+    outbox: Arc<Outbox>,
+    span: Span,
+}
 
-    pub async fn associate_account(&self, installation_id: Uuid, account_id: i64) {
-        let mut installations_by_account = self.installations_by_account.write().await;
-        let mut account_by_installation = self.account_by_installation.write().await;
-        account_by_installation.insert(installation_id, account_id);
-        let installations = installations_by_account
-            .entry(account_id)
-            .or_insert_with(|| HashSet::new());
-        installations.insert(installation_id);
-    }
+#[derive(Deserialize)]
+pub struct WebsocketOptions {
+    format: Option<String>,
+}
 
-    pub async fn disconnect(&self, installation_id: Uuid) {
-        let mut installations_by_account = self.installations_by_account.write().await;
-        let account_by_installation = self.account_by_installation.read().await;
-        let mut senders = self.senders.write().await;
-
-        senders.remove(&installation_id);
-        if let Some(account_id) = account_by_installation.get(&installation_id) {
-            let remove_account =
-                if let Some(installations) = installations_by_account.get_mut(account_id) {
-                    installations.remove(&installation_id);
-                    installations.len() == 0
-                } else {
-                    false
-                };
-            if remove_account {
-                installations_by_account.remove(&account_id);
+impl WebsocketOptions {
+    /// The requested wire format. Clients asking for one this server doesn't
+    /// know get the default rather than a failed upgrade they can't explain.
+    fn format(&self) -> WireFormat {
+        match self.format.as_deref().map(str::parse) {
+            Some(Ok(format)) => format,
+            Some(Err(err)) => {
+                let default = WireFormat::default();
+                warn!(error = %err, format = default.name(), "Using the default wire format");
+                default
             }
-        }
-    }
-
-    pub async fn send_to_installation_id(&self, installation_id: Uuid, message: ServerResponse) {
-        let senders = self.senders.write().await;
-        if let Some(sender) = senders.get(&installation_id) {
-            sender.send(message).unwrap_or_default();
+            None => WireFormat::default(),
         }
     }
 }
 
-#[derive(Default)]
-pub struct ConnectedClient {
-    installation_id: Option<Uuid>,
+pub async fn main(websocket: WebSocket, options: WebsocketOptions, remote_address: Option<IpAddr>) {
+    // The ids are recorded once the client authenticates.
+    let span = info_span!(
+        "session",
+        ?remote_address,
+        installation_id = field::Empty,
+        account_id = field::Empty,
+    );
+    run(websocket, options, remote_address, span.clone())
+        .instrument(span)
+        .await
 }
 
-pub async fn main(websocket: WebSocket) {
+async fn run(
+    websocket: WebSocket,
+    options: WebsocketOptions,
+    remote_address: Option<IpAddr>,
+    span: Span,
+) {
+    let format = options.format();
     let (mut tx, mut rx) = websocket.split();
-    let (sender, transmission_receiver) = unbounded();
+    let outbox = Outbox::new(CONFIG.outbox);
 
-    tokio::spawn(async move {
-        while let Ok(response) = transmission_receiver.recv() {
-            tx.send(Message::binary(bincode::serialize(&response).unwrap()))
-                .await
-                .unwrap_or_default()
+    let mut sender_task = tokio::spawn({
+        let outbox = outbox.clone();
+        let session = span.clone();
+        // Returns why it stopped, and the message it failed to send if any.
+        async move {
+            let (reason, unsent) = loop {
+                let response = match outbox.recv().await {
+                    Ok(response) => response,
+                    Err(CloseReason::Closed) => break (DisconnectReason::Closed, None),
+                    Err(CloseReason::Overflowed) => break (DisconnectReason::Overflowed, None),
+                };
+                // Logins can complete on another replica, so this is the one
+                // place every path to an authenticated session passes through.
+                if let ServerResponse::Authenticated { profile } = &response.response {
+                    session.record("account_id", profile.id);
+                }
+                let bytes = match format.encode(&response) {
+                    Ok(bytes) => bytes,
+                    Err(err) => {
+                        error!(error = %err, "Error encoding response");
+                        continue;
+                    }
+                };
+                let message = if format.is_text() {
+                    Message::text(String::from_utf8(bytes).expect("Text formats produce UTF-8"))
+                } else {
+                    Message::binary(bytes)
+                };
+                if tx.send(message).await.is_err() {
+                    break (DisconnectReason::Error, Some(response));
+                }
+            };
+            tx.close().await.unwrap_or_default();
+            (reason, unsent)
         }
+        .instrument(span.clone())
     });
 
-    let mut client = ConnectedClient::default();
-    while let Some(result) = rx.next().await {
-        match result {
-            Ok(message) => match bincode::deserialize::<ServerRequest>(message.as_bytes()) {
-                Ok(request) => {
-                    if let Err(err) = client
-                        .handle_websocket_request(request, sender.clone())
-                        .await
-                    {
-                        sender
-                            .send(ServerResponse::Error {
-                                message: Some(err.to_string()),
-                            })
-                            .unwrap_or_default();
+    metrics::WEBSOCKETS_CONNECTED.inc();
+    let mut client = ConnectedClient {
+        installation_id: None,
+        remote_address,
+        outbox: outbox.clone(),
+        span,
+    };
+    let heartbeat_interval = CONFIG.heartbeat_interval();
+    let mut heartbeat = tokio::time::interval(heartbeat_interval);
+    let mut last_received = Instant::now();
+    let mut sender_finished = false;
+    let mut unsent = Vec::new();
+    let reason = loop {
+        tokio::select! {
+            result = rx.next() => match result {
+                Some(Ok(message)) => {
+                    last_received = Instant::now();
+                    match format.decode::<RequestEnvelope>(message.as_bytes()) {
+                        Ok(envelope) => client.handle_envelope(envelope).await,
+                        Err(err) => {
+                            metrics::DECODE_FAILURES
+                                .with_label_values(&[format.name()])
+                                .inc();
+                            debug!(error = %err, "Error decoding request");
+                        }
                     }
                 }
-                Err(err) => println!("Bincode error: {}", err),
+                Some(Err(err)) => {
+                    info!(error = %err, "Error on websocket");
+                    break DisconnectReason::Error;
+                }
+                None => break DisconnectReason::Closed,
             },
-            Err(err) => {
-                println!("Error on websocket: {}", err);
-                return;
+            result = &mut sender_task => {
+                sender_finished = true;
+                let (reason, failed) = result.unwrap_or((DisconnectReason::Error, None));
+                unsent.extend(failed);
+                if reason == DisconnectReason::Overflowed {
+                    info!("Disconnecting slow client");
+                }
+                break reason;
+            },
+            _ = heartbeat.tick() => {
+                if last_received.elapsed() > shared::heartbeat_timeout(heartbeat_interval) {
+                    info!("Disconnecting unresponsive client");
+                    break DisconnectReason::Unresponsive;
+                }
+                outbox.push(ResponseEnvelope {
+                    request_id: None,
+                    response: ServerResponse::Ping,
+                });
             }
         }
+    };
+
+    // Detach from the session before closing the outbox, so nothing else is
+    // queued for this connection, then let the sender task flush what's left.
+    // Whatever it couldn't send goes back to the session for a resume.
+    let session = client.disconnect(reason);
+    outbox.close();
+    if !sender_finished {
+        let (_, failed) = sender_task.await.unwrap_or((DisconnectReason::Error, None));
+        unsent.extend(failed);
+    }
+    while let Ok(envelope) = outbox.recv().await {
+        unsent.push(envelope);
+    }
+    if let Some((installation_id, resume_token)) = session {
+        CONNECTED_CLIENTS.return_unsent(installation_id, resume_token, unsent);
     }
+    metrics::WEBSOCKETS_CONNECTED.dec();
 }
 
 impl ConnectedClient {
+    /// Returns the installation id and resume token of the session this
+    /// connection was detached from.
+    fn disconnect(self, reason: DisconnectReason) -> Option<(Uuid, Uuid)> {
+        let installation_id = self.installation_id?;
+        CONNECTED_CLIENTS
+            .disconnect(installation_id, &self.outbox, reason)
+            .map(|resume_token| (installation_id, resume_token))
+    }
+
+    async fn handle_envelope(&mut self, envelope: RequestEnvelope) {
+        let responder = Responder {
+            request_id: envelope.id,
+            outbox: self.outbox.clone(),
+        };
+        let kind = envelope.request.kind();
+
+        if let Err(retry_after) = RATE_LIMITER.check(kind, &self.rate_limit_keys(&envelope.request))
+        {
+            metrics::REQUESTS
+                .with_label_values(&[kind, "rate_limited"])
+                .inc();
+            responder.send(ServerResponse::RateLimited { retry_after });
+            return;
+        }
+
+        let timer = metrics::REQUEST_DURATION
+            .with_label_values(&[kind])
+            .start_timer();
+        let result = self
+            .handle_websocket_request(envelope.request, &responder)
+            .instrument(debug_span!("request", kind, request_id = ?envelope.id))
+            .await;
+        timer.observe_duration();
+
+        let outcome = match result {
+            Ok(()) => "ok",
+            Err(err) => {
+                let code = error_code(&err);
+                if code == ErrorCode::Internal {
+                    error!(error = ?err, kind, "Error handling request");
+                }
+                responder.send(ServerResponse::Error { code });
+                "error"
+            }
+        };
+        metrics::REQUESTS.with_label_values(&[kind, outcome]).inc();
+    }
+
+    fn set_installation_id(&mut self, installation_id: Uuid) {
+        self.installation_id = Some(installation_id);
+        self.span
+            .record("installation_id", field::display(installation_id));
+    }
+
+    fn rate_limit_keys(&self, request: &ServerRequest) -> Vec<RateLimitKey> {
+        let installation_id = match request {
+            ServerRequest::Authenticate {
+                installation_id, ..
+            } => installation_id.or(self.installation_id),
+            _ => self.installation_id,
+        };
+
+        let installation = installation_id.map(RateLimitKey::Installation);
+        let address = self.remote_address.map(RateLimitKey::RemoteAddress);
+        match request {
+            // Every client authenticates on connecting and heartbeats at the
+            // same rate, so limiting these by address would cut off players
+            // sharing one. Only clients that haven't said who they are yet are
+            // limited by address.
+            ServerRequest::Authenticate { .. } | ServerRequest::Ping | ServerRequest::Pong => {
+                installation.or(address).into_iter().collect()
+            }
+            _ => address.into_iter().chain(installation).collect(),
+        }
+    }
+
     async fn handle_websocket_request(
         &mut self,
         request: ServerRequest,
-        responder: Sender<ServerResponse>,
+        responder: &Responder,
     ) -> Result<(), anyhow::Error> {
         match request {
             ServerRequest::Authenticate {
                 installation_id,
                 version,
+                resume_token,
             } => {
-                if &version != shared::PROTOCOL_VERSION {
-                    responder
-                        .send(ServerResponse::Error {
-                            message: Some("An update is available".to_owned()),
-                        })
-                        .unwrap_or_default();
-                    return Ok(());
+                match check_client_version(&version) {
+                    VersionCheck::Supported => {}
+                    VersionCheck::UpdateRequired => {
+                        responder.send(ServerResponse::UpdateRequired {
+                            minimum: MINIMUM_CLIENT_VERSION.to_owned(),
+                            download_url: CONFIG.client_download_url.to_string(),
+                        });
+                        return Ok(());
+                    }
+                    VersionCheck::TooNew => {
+                        return Err(RequestError(ErrorCode::UnsupportedVersion).into());
+                    }
+                }
+                if let (Some(installation_id), Some(resume_token)) = (installation_id, resume_token)
+                {
+                    if let Some(session) = CONNECTED_CLIENTS.resume(
+                        installation_id,
+                        resume_token,
+                        responder.outbox.clone(),
+                    ) {
+                        self.set_installation_id(installation_id);
+                        responder.send(ServerResponse::SessionResumed {
+                            heartbeat_interval: CONFIG.heartbeat_interval(),
+                        });
+                        if let Some(profile) = session.profile {
+                            responder.send(ServerResponse::Authenticated { profile });
+                        }
+                        return Ok(());
+                    }
                 }
-                self.installation_id = Some(match installation_id {
+
+                self.set_installation_id(match installation_id {
                     Some(installation_id) => installation_id,
                     None => {
                         let installation_id = Uuid::new_v4();
-                        responder
-                            .send(ServerResponse::AdoptInstallationId {
-                                installation_id: installation_id,
-                            })
-                            .unwrap_or_default();
+                        responder.send(ServerResponse::AdoptInstallationId { installation_id });
                         installation_id
                     }
                 });
 
                 let pool = pg();
-                let installation = sqlx::query_as!(
-                    Installation,
-                    "SELECT * FROM installation_lookup($1)",
-                    self.installation_id
+                let installation = metrics::time_query(
+                    "installation_lookup",
+                    sqlx::query_as!(
+                        Installation,
+                        "SELECT * FROM installation_lookup($1)",
+                        self.installation_id
+                    )
+                    .fetch_one(&pool),
                 )
-                .fetch_one(&pool)
                 .await?;
 
-                CONNECTED_CLIENTS
-                    .connect(installation.id, responder.clone())
-                    .await;
+                let resume_token =
+                    CONNECTED_CLIENTS.connect(installation.id, responder.outbox.clone());
+                responder.send(ServerResponse::SessionStarted {
+                    resume_token,
+                    heartbeat_interval: CONFIG.heartbeat_interval(),
+                });
 
-                if let Some(account_id) = installation.account_id {
-                    let profile = sqlx::query_as!(
-                        UserProfile,
-                        "SELECT id, username FROM installation_profile($1)",
-                        installation.id,
+                if installation.account_id.is_some() {
+                    let profile = metrics::time_query(
+                        "installation_profile",
+                        sqlx::query_as!(
+                            UserProfile,
+                            "SELECT id, username FROM installation_profile($1)",
+                            installation.id,
+                        )
+                        .fetch_one(&pool),
                     )
-                    .fetch_one(&pool)
                     .await?;
 
-                    CONNECTED_CLIENTS
-                        .associate_account(installation.id, account_id)
-                        .await;
-                    responder
-                        .send(ServerResponse::Authenticated { profile })
-                        .unwrap_or_default();
+                    CONNECTED_CLIENTS.associate_account(installation.id, profile.clone());
+                    responder.send(ServerResponse::Authenticated { profile });
                 }
                 Ok(())
             }
+            ServerRequest::Ping => {
+                responder.send(ServerResponse::Pong);
+                Ok(())
+            }
+            ServerRequest::Pong => Ok(()),
             ServerRequest::AuthenticationUrl => {
-                if let Some(installation_id) = self.installation_id {
-                    responder
-                        .send(ServerResponse::AuthenticateAtUrl {
-                            url: itchio_authorization_url(installation_id),
-                        })
-                        .unwrap_or_default();
-                }
+                let installation_id = self
+                    .installation_id
+                    .ok_or(RequestError(ErrorCode::Unauthenticated))?;
+                let state =
+                    oauth_state::create(installation_id, CONFIG.oauth.state_lifetime_secs).await?;
+                responder.send(ServerResponse::AuthenticateAtUrl {
+                    url: IDENTITY_PROVIDER.authorization_url(&state),
+                });
                 Ok(())
             }
         }
     }
 }
 
-impl Drop for ConnectedClient {
-    fn drop(&mut self) {
-        if let Some(installation_id) = self.installation_id {
-            block_on(CONNECTED_CLIENTS.disconnect(installation_id));
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbox::OutboxSettings;
+    use crate::rate_limit::{RateLimit, RateLimiter, RateLimits};
+
+    #[test]
+    fn unknown_formats_fall_back_to_the_default() {
+        let options = |format: Option<&str>| WebsocketOptions {
+            format: format.map(str::to_owned),
+        };
+        assert_eq!(options(Some("json")).format(), WireFormat::Json);
+        assert_eq!(
+            options(Some("carrier-pigeon")).format(),
+            WireFormat::default()
+        );
+        assert_eq!(options(None).format(), WireFormat::default());
     }
-}
 
-#[cfg(debug_assertions)]
-static REDIRECT_URI: &'static str = "http://localhost:7878/auth/itchio_callback";
-#[cfg(not(debug_assertions))]
-static REDIRECT_URI: &'static str = "https://cantina.khonsu.gg/auth/itchio_callback";
-
-fn itchio_authorization_url(installation_id: Uuid) -> String {
-    Url::parse_with_params(
-        "https://itch.io/user/oauth",
-        &[
-            ("client_id", env("OAUTH_CLIENT_ID")),
-            ("scope", "profile:me".to_owned()),
-            ("response_type", "token".to_owned()),
-            ("redirect_uri", REDIRECT_URI.to_owned()),
-            ("state", installation_id.to_string()),
-        ],
-    )
-    .unwrap()
-    .to_string()
+    #[test]
+    fn heartbeats_and_logins_are_limited_per_installation() {
+        let interval = shared::heartbeat_interval(None);
+        let mut limits = RateLimits::default();
+        limits.limit_heartbeats(interval);
+        let limiter = RateLimiter::new(limits);
+        let client = |installation_id| ConnectedClient {
+            installation_id,
+            remote_address: Some([10, 0, 0, 1].into()),
+            outbox: Outbox::new(OutboxSettings::default()),
+            span: Span::none(),
+        };
+        let ping = |client: &ConnectedClient| {
+            limiter.check("ping", &client.rate_limit_keys(&ServerRequest::Ping))
+        };
+
+        // Players sharing an address each get the full allowance.
+        let players = (0..5)
+            .map(|_| client(Some(Uuid::new_v4())))
+            .collect::<Vec<_>>();
+        for player in &players {
+            for _ in 0..RateLimit::for_heartbeats(interval).burst {
+                assert!(ping(player).is_ok());
+            }
+        }
+        assert!(ping(&players[0]).is_err());
+        assert!(ping(&client(None)).is_ok());
+
+        // So does authenticating, which every connection starts with.
+        let authenticate = |installation_id| {
+            let request = ServerRequest::Authenticate {
+                version: shared::PROTOCOL_VERSION.to_owned(),
+                installation_id,
+                resume_token: None,
+            };
+            limiter.check("authenticate", &client(None).rate_limit_keys(&request))
+        };
+        let burst = RateLimits::default().get("authenticate").unwrap().burst;
+        for _ in 0..2 * burst {
+            assert!(authenticate(Some(Uuid::new_v4())).is_ok());
+        }
+        for _ in 0..burst {
+            assert!(authenticate(None).is_ok());
+        }
+        assert!(authenticate(None).is_err());
+
+        // Other requests are still limited by address as well.
+        let authentication_url = |client: &ConnectedClient| {
+            let keys = client.rate_limit_keys(&ServerRequest::AuthenticationUrl);
+            limiter.check("authentication_url", &keys)
+        };
+        for _ in 0..RateLimits::default()
+            .get("authentication_url")
+            .unwrap()
+            .burst
+        {
+            assert!(authentication_url(&players[0]).is_ok());
+        }
+        assert!(authentication_url(&players[1]).is_err());
+    }
 }
@@ -0,0 +1,296 @@
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+/// Frames larger than this many bytes are compressed by `Compressed`.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+/// The largest frame `Compressed` will decompress, to guard against
+/// decompression bombs.
+pub const MAX_DECOMPRESSED_SIZE: u64 = 16 * 1024 * 1024;
+
+const UNCOMPRESSED_FLAG: u8 = 0;
+const DEFLATE_FLAG: u8 = 1;
+
+/// Converts protocol messages to and from the bytes sent over the websocket.
+pub trait Codec {
+    fn encode<T: serde::Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: serde::Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: serde::Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Wraps another codec, prefixing every frame with a flag byte that says
+/// whether the rest of the frame is deflate-compressed. Only frames larger
+/// than `threshold` bytes are compressed.
+pub struct Compressed<C> {
+    pub codec: C,
+    pub threshold: usize,
+}
+
+impl Compressed<BincodeCodec> {
+    pub fn bincode() -> Self {
+        Self {
+            codec: BincodeCodec,
+            threshold: COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+impl<C: Codec> Codec for Compressed<C> {
+    fn encode<T: serde::Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        let bytes = self.codec.encode(value)?;
+        if bytes.len() > self.threshold {
+            let mut encoder = DeflateEncoder::new(vec![DEFLATE_FLAG], Compression::default());
+            encoder.write_all(&bytes)?;
+            Ok(encoder.finish()?)
+        } else {
+            let mut frame = Vec::with_capacity(bytes.len() + 1);
+            frame.push(UNCOMPRESSED_FLAG);
+            frame.extend_from_slice(&bytes);
+            Ok(frame)
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        match bytes.split_first() {
+            Some((&UNCOMPRESSED_FLAG, payload)) => self.codec.decode(payload),
+            Some((&DEFLATE_FLAG, payload)) => {
+                let mut decompressed = Vec::new();
+                DeflateDecoder::new(payload)
+                    .take(MAX_DECOMPRESSED_SIZE + 1)
+                    .read_to_end(&mut decompressed)?;
+                if decompressed.len() as u64 > MAX_DECOMPRESSED_SIZE {
+                    return Err(CodecError::FrameTooLarge);
+                }
+                self.codec.decode(&decompressed)
+            }
+            Some((&flag, _)) => Err(CodecError::UnknownFrameFlag(flag)),
+            None => Err(CodecError::EmptyFrame),
+        }
+    }
+}
+
+/// The codec used by a connection. Clients pick one with the `format` query
+/// parameter when connecting, e.g. `/ws?format=json`. JSON is meant for
+/// debugging and non-Rust tooling. Plain bincode is used when a client
+/// doesn't ask for a format.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum WireFormat {
+    #[default]
+    #[serde(rename = "bincode")]
+    Bincode,
+    #[serde(rename = "bincode-deflate")]
+    BincodeDeflate,
+    #[serde(rename = "json")]
+    Json,
+}
+
+impl WireFormat {
+    /// Whether frames should be sent as websocket text messages rather than binary.
+    pub fn is_text(&self) -> bool {
+        match self {
+            WireFormat::Bincode | WireFormat::BincodeDeflate => false,
+            WireFormat::Json => true,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WireFormat::Bincode => "bincode",
+            WireFormat::BincodeDeflate => "bincode-deflate",
+            WireFormat::Json => "json",
+        }
+    }
+}
+
+impl FromStr for WireFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(WireFormat::Bincode),
+            "bincode-deflate" => Ok(WireFormat::BincodeDeflate),
+            "json" => Ok(WireFormat::Json),
+            other => Err(format!("Unknown wire format: {}", other)),
+        }
+    }
+}
+
+impl Codec for WireFormat {
+    fn encode<T: serde::Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            WireFormat::Bincode => BincodeCodec.encode(value),
+            WireFormat::BincodeDeflate => Compressed::bincode().encode(value),
+            WireFormat::Json => JsonCodec.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        match self {
+            WireFormat::Bincode => BincodeCodec.decode(bytes),
+            WireFormat::BincodeDeflate => Compressed::bincode().decode(bytes),
+            WireFormat::Json => JsonCodec.decode(bytes),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Bincode(bincode::Error),
+    Json(serde_json::Error),
+    Io(std::io::Error),
+    EmptyFrame,
+    UnknownFrameFlag(u8),
+    FrameTooLarge,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Bincode(err) => write!(f, "bincode error: {}", err),
+            CodecError::Json(err) => write!(f, "json error: {}", err),
+            CodecError::Io(err) => write!(f, "compression error: {}", err),
+            CodecError::EmptyFrame => f.write_str("empty frame"),
+            CodecError::UnknownFrameFlag(flag) => write!(f, "unknown frame flag: {}", flag),
+            CodecError::FrameTooLarge => f.write_str("decompressed frame is too large"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<bincode::Error> for CodecError {
+    fn from(err: bincode::Error) -> Self {
+        CodecError::Bincode(err)
+    }
+}
+
+impl From<serde_json::Error> for CodecError {
+    fn from(err: serde_json::Error) -> Self {
+        CodecError::Json(err)
+    }
+}
+
+impl From<std::io::Error> for CodecError {
+    fn from(err: std::io::Error) -> Self {
+        CodecError::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ResponseEnvelope, ServerResponse, UserProfile};
+
+    fn small_response() -> ResponseEnvelope {
+        ResponseEnvelope {
+            request_id: Some(1),
+            response: ServerResponse::Authenticated {
+                profile: UserProfile {
+                    id: 1,
+                    username: "ecton".to_owned(),
+                },
+            },
+        }
+    }
+
+    fn large_response() -> ResponseEnvelope {
+        ResponseEnvelope {
+            request_id: None,
+            response: ServerResponse::AuthenticateAtUrl {
+                url: "https://cantina.khonsu.gg/".repeat(100),
+            },
+        }
+    }
+
+    fn assert_round_trips<C: Codec>(codec: &C, envelope: &ResponseEnvelope) -> Vec<u8> {
+        let frame = codec.encode(envelope).unwrap();
+        let decoded: ResponseEnvelope = codec.decode(&frame).unwrap();
+        assert_eq!(format!("{:?}", envelope), format!("{:?}", decoded));
+        frame
+    }
+
+    #[test]
+    fn small_frames_are_not_compressed() {
+        let frame = assert_round_trips(&Compressed::bincode(), &small_response());
+        assert_eq!(frame[0], UNCOMPRESSED_FLAG);
+        assert_eq!(
+            &frame[1..],
+            &bincode::serialize(&small_response()).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn large_frames_are_compressed() {
+        let frame = assert_round_trips(&Compressed::bincode(), &large_response());
+        assert_eq!(frame[0], DEFLATE_FLAG);
+        assert!(frame.len() < bincode::serialize(&large_response()).unwrap().len());
+    }
+
+    #[test]
+    fn every_wire_format_round_trips() {
+        for format in &[
+            WireFormat::Bincode,
+            WireFormat::BincodeDeflate,
+            WireFormat::Json,
+        ] {
+            assert_round_trips(format, &small_response());
+            assert_round_trips(format, &large_response());
+            assert_eq!(format.name().parse::<WireFormat>().unwrap(), *format);
+        }
+    }
+
+    #[test]
+    fn invalid_frames_are_rejected() {
+        let codec = Compressed::bincode();
+        assert!(matches!(
+            codec.decode::<ResponseEnvelope>(&[]),
+            Err(CodecError::EmptyFrame)
+        ));
+        assert!(matches!(
+            codec.decode::<ResponseEnvelope>(&[7, 0, 0]),
+            Err(CodecError::UnknownFrameFlag(7))
+        ));
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let codec = Compressed {
+            codec: BincodeCodec,
+            threshold: 0,
+        };
+        let bomb = vec![0u8; MAX_DECOMPRESSED_SIZE as usize + 1];
+        let frame = codec.encode(&bomb).unwrap();
+        assert!(matches!(
+            codec.decode::<Vec<u8>>(&frame),
+            Err(CodecError::FrameTooLarge)
+        ));
+    }
+}
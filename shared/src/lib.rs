@@ -1,23 +1,197 @@
 use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
 use uuid::Uuid;
 
-pub const PROTOCOL_VERSION: &'static str = "0.0.1";
+mod codec;
+pub use codec::{
+    BincodeCodec, Codec, CodecError, Compressed, JsonCodec, WireFormat, COMPRESSION_THRESHOLD,
+    MAX_DECOMPRESSED_SIZE,
+};
+
+pub const PROTOCOL_VERSION: &str = "0.1.0";
+
+// Each side sends `Ping` every interval and expects a `Pong` back. A
+// connection that has been silent for `MISSED_HEARTBEAT_LIMIT` intervals is
+// considered dead. The server tells clients its interval when their session
+// starts or resumes.
+pub const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 5_000;
+pub const MISSED_HEARTBEAT_LIMIT: u32 = 3;
+
+/// The heartbeat interval, unless `interval_ms` overrides the default.
+pub fn heartbeat_interval(interval_ms: Option<u64>) -> Duration {
+    Duration::from_millis(interval_ms.unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_MS))
+}
+
+/// How long a connection heartbeating every `interval` can be silent before
+/// it's considered dead.
+pub fn heartbeat_timeout(interval: Duration) -> Duration {
+    interval * MISSED_HEARTBEAT_LIMIT
+}
+
+pub type RequestId = u64;
+
+/// A request sent to the server. If `id` is set, every response generated while
+/// handling the request is sent back with a matching `request_id`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RequestEnvelope {
+    pub id: Option<RequestId>,
+    pub request: ServerRequest,
+}
+
+/// A response from the server. `request_id` is `None` for messages that
+/// weren't caused by a client request, such as login notifications.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ResponseEnvelope {
+    pub request_id: Option<RequestId>,
+    pub response: ServerResponse,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ServerRequest {
     Authenticate {
         version: String,
         installation_id: Option<Uuid>,
+        resume_token: Option<Uuid>,
     },
     AuthenticationUrl,
+    Ping,
+    Pong,
+}
+
+impl ServerRequest {
+    /// A stable name for the kind of request, used for configuration and metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerRequest::Authenticate { .. } => "authenticate",
+            ServerRequest::AuthenticationUrl => "authentication_url",
+            ServerRequest::Ping => "ping",
+            ServerRequest::Pong => "pong",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ServerResponse {
-    AdoptInstallationId { installation_id: Uuid },
-    AuthenticateAtUrl { url: String },
-    Authenticated { profile: UserProfile },
-    Error { message: Option<String> },
+    AdoptInstallationId {
+        installation_id: Uuid,
+    },
+    AuthenticateAtUrl {
+        url: String,
+    },
+    SessionStarted {
+        resume_token: Uuid,
+        heartbeat_interval: Duration,
+    },
+    SessionResumed {
+        heartbeat_interval: Duration,
+    },
+    Authenticated {
+        profile: UserProfile,
+    },
+    LoginFailed {
+        reason: LoginFailure,
+    },
+    UpdateRequired {
+        minimum: String,
+        download_url: String,
+    },
+    RateLimited {
+        retry_after: Duration,
+    },
+    ServerShuttingDown {
+        reconnect_after: Duration,
+    },
+    Error {
+        code: ErrorCode,
+    },
+    Ping,
+    Pong,
+}
+
+/// The reason a request failed. Servers never send free-form error text, so
+/// internal details can't leak to players and clients can act on the code.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    Unauthenticated,
+    RateLimited,
+    InvalidRequest,
+    UnsupportedVersion,
+    NotConnected,
+    Timeout,
+    Internal,
+}
+
+impl ErrorCode {
+    /// A stable identifier for looking up a translated message.
+    pub fn message_key(&self) -> &'static str {
+        match self {
+            ErrorCode::Unauthenticated => "error.unauthenticated",
+            ErrorCode::RateLimited => "error.rate_limited",
+            ErrorCode::InvalidRequest => "error.invalid_request",
+            ErrorCode::UnsupportedVersion => "error.unsupported_version",
+            ErrorCode::NotConnected => "error.not_connected",
+            ErrorCode::Timeout => "error.timeout",
+            ErrorCode::Internal => "error.internal",
+        }
+    }
+
+    /// The English message, used when no translation is available.
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorCode::Unauthenticated => "You need to log in first.",
+            ErrorCode::RateLimited => "Too many requests. Please wait a moment.",
+            ErrorCode::InvalidRequest => "The request was invalid.",
+            ErrorCode::UnsupportedVersion => "This server does not support your version yet.",
+            ErrorCode::NotConnected => "Not connected to the server.",
+            ErrorCode::Timeout => "The server did not respond in time.",
+            ErrorCode::Internal => "Something went wrong on the server.",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+/// Why logging in at the identity provider didn't work.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoginFailure {
+    /// The provider turned the login down, e.g. because the token expired.
+    Rejected,
+    /// The provider couldn't be reached, or answered with something
+    /// unexpected.
+    ProviderUnavailable,
+    Internal,
+}
+
+impl LoginFailure {
+    /// A stable identifier for looking up a translated message.
+    pub fn message_key(&self) -> &'static str {
+        match self {
+            LoginFailure::Rejected => "login_failure.rejected",
+            LoginFailure::ProviderUnavailable => "login_failure.provider_unavailable",
+            LoginFailure::Internal => "login_failure.internal",
+        }
+    }
+
+    /// The English message, used when no translation is available.
+    pub fn message(&self) -> &'static str {
+        match self {
+            LoginFailure::Rejected => "Your login wasn't accepted. Please try again.",
+            LoginFailure::ProviderUnavailable => {
+                "The login service couldn't be reached. Please try again later."
+            }
+            LoginFailure::Internal => "Something went wrong on the server.",
+        }
+    }
+}
+
+impl std::fmt::Display for LoginFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]